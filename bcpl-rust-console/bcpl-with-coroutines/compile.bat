@@ -17,8 +17,18 @@ if not exist "%~1" (
     exit /b 1
 )
 
-REM Concatenate syni and trni
-set "ICINT=.\target\x86_64-pc-windows-gnu\release\icint.exe"
+REM Build the shared interpreter crate with the coroutine K-codes enabled,
+REM as compile.sh does. Without cargo, use an icint.exe cross-compiled as in
+REM Windows.md.
+set "ICINT=.\target\release\icint.exe"
+where cargo >nul 2>nul
+if not errorlevel 1 (
+    cargo build --release --quiet --features coroutines --manifest-path "%ROOT%..\Cargo.toml" --target-dir "%ROOT%target"
+    if errorlevel 1 exit /b 1
+) else if not exist "%ICINT%" (
+    set "ICINT=.\target\x86_64-pc-windows-gnu\release\icint.exe"
+)
+
 set "SYN=.\syni"
 set "TRN=.\trni"
 set "CGI=.\cgi"
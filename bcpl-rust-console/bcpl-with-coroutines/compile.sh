@@ -33,6 +33,10 @@ if [ ! -f "$CGI" ]; then
     CGI="$ROOT_DIR/../cgi"
 fi
 
+# Build the shared interpreter crate with the coroutine K-codes enabled
+cargo build --release --quiet --features coroutines \
+    --manifest-path "$ROOT_DIR/../Cargo.toml" --target-dir "$ROOT_DIR/target"
+
 mkdir -p "$TMP_DIR"
 
 to_crlf() {
@@ -65,7 +69,7 @@ echo "Compiling OCODE to INTCODE..."
 
 # Run INTCODE
 echo "Running INTCODE..."
-timeout 10s "$ICINT" "$TMP_DIR/INTCODE" $2 $3
+"$ICINT" "$TMP_DIR/INTCODE" --max-time 10 $2 $3
 
 if [ -f "$TMP_DIR/output.txt" ]; then
     cp "$TMP_DIR/output.txt" "$ROOT_DIR/output.txt"
@@ -0,0 +1,516 @@
+// INTCODE assembler. Reads the current input stream a character at a time,
+// as icint always has, and keeps track of where it is in the source so that
+// problems can be reported with file, line, column and an excerpt, and so
+// that a listing can map each line to the words it produced.
+
+use crate::*;
+
+/// Largest label number the assembler accepts.
+pub const MAX_LABEL: usize = i16::MAX as usize;
+
+// A label of the current section. References are back-patched when the label
+// is defined; `first` is where the first one was, for UNSET LABEL.
+#[derive(Clone, Default)]
+enum Label {
+    #[default]
+    Unused,
+    Unset {
+        refs: Vec<usize>,
+        first: (usize, usize),
+    },
+    Defined(usize),
+}
+
+// Source position tracking for the section being assembled.
+#[derive(Default)]
+pub(crate) struct Source {
+    name: String,
+    lines: Vec<String>,
+    line: usize,
+    col: usize,
+    at_line_start: bool,
+    after_cr: bool,
+    // Start of the directive being assembled
+    start: (usize, usize),
+    // Labels of the current section, grown as label numbers are seen
+    labels: Vec<Label>,
+    diagnostics: Vec<Diagnostic>,
+    // First and last+1 address of the words placed by each line
+    words: Vec<Option<(usize, usize)>>,
+    // Lines already written to the listing
+    listed: usize,
+}
+
+impl Source {
+    fn new(name: &str) -> Self {
+        Source {
+            name: name.to_string(),
+            at_line_start: true,
+            ..Source::default()
+        }
+    }
+
+    fn here(&self) -> (usize, usize) {
+        (self.line, self.col)
+    }
+
+    // Records a word placed by the directive being assembled.
+    fn placed(&mut self, addr: usize) {
+        let i = self.start.0.saturating_sub(1);
+        if i >= self.words.len() {
+            self.words.resize(i + 1, None);
+        }
+        let range = self.words[i].get_or_insert((addr, addr + 1));
+        range.0 = range.0.min(addr);
+        range.1 = range.1.max(addr + 1);
+    }
+
+    fn diagnose(&mut self, error: VmError, (line, column): (usize, usize)) {
+        self.diagnostics.push(Diagnostic {
+            error,
+            file: self.name.clone(),
+            line,
+            column,
+            excerpt: String::new(),
+        });
+    }
+
+    // Fills in the excerpts once the offending lines have been read in full.
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        let mut diagnostics = std::mem::take(&mut self.diagnostics);
+        diagnostics.sort_by_key(|d| (d.line, d.column));
+        for d in &mut diagnostics {
+            if let Some(text) = d.line.checked_sub(1).and_then(|i| self.lines.get(i)) {
+                d.excerpt = text.clone();
+            }
+        }
+        diagnostics
+    }
+}
+
+impl BcplState {
+    pub(crate) fn assemble(&mut self, name: &str) -> Result<(), VmError> {
+        self.src = Source::new(name);
+        // The assembler may write anywhere, and extends the code area.
+        self.guarded = 0..0;
+        let result = self.assemble_sections();
+        self.src = Source::default();
+        self.guard();
+        result
+    }
+
+    fn assemble_sections(&mut self) -> Result<(), VmError> {
+        self.cp = 0;
+
+        self.rch();
+
+        loop {
+            self.src.start = self.src.here();
+            let pos = self.src.start;
+            match self.statement() {
+                Ok(true) => {}
+                Ok(false) => break,
+                // Running out of store is not worth going on from.
+                Err(e @ VmError::BadAccess { .. }) => {
+                    let e = e.at(self.lomem, 0);
+                    self.src.diagnose(e, pos);
+                    self.skip_line();
+                    break;
+                }
+                Err(e) => {
+                    let e = e.at(self.lomem, 0);
+                    self.src.diagnose(e, pos);
+                    self.skip_line();
+                }
+            }
+        }
+
+        self.list_section(true);
+        if self.src.diagnostics.is_empty() {
+            if !self.src.labels.is_empty() {
+                self.keep_labels();
+            }
+            Ok(())
+        } else {
+            Err(VmError::Assembly(self.src.take_diagnostics()))
+        }
+    }
+
+    // Assembles one directive. Returns false at the end of the input.
+    fn statement(&mut self) -> Result<bool, VmError> {
+        // Check for label definition (digit)
+        if self.ch >= ASC_0 as Word && self.ch <= ASC_9 as Word {
+            let n = self.rdlabel()?;
+            let label = std::mem::replace(&mut self.src.labels[n], Label::Defined(self.lomem));
+            match label {
+                Label::Defined(addr) => {
+                    self.src.labels[n] = Label::Defined(addr);
+                    return Err(VmError::DuplicateLabel { label: n as Word, at: Context::default() });
+                }
+                Label::Unset { refs, .. } => {
+                    for a in refs {
+                        let w = self.load(a)?;
+                        self.store(a, w.wrapping_add(self.lomem as Word))?;
+                    }
+                }
+                Label::Unused => {}
+            }
+            self.cp = 0;
+            return Ok(true);
+        }
+
+        match self.ch as u8 {
+            b'$' | ASC_SPACE | ASC_LF => self.rch(),
+            b'L' => self.process_instruction(F0_L)?,
+            b'S' => self.process_instruction(F1_S)?,
+            b'A' => self.process_instruction(F2_A)?,
+            b'J' => self.process_instruction(F3_J)?,
+            b'T' => self.process_instruction(F4_T)?,
+            b'F' => self.process_instruction(F5_F)?,
+            b'K' => self.process_instruction(F6_K)?,
+            b'X' => self.process_instruction(F7_X)?,
+            b'C' => {
+                self.rch();
+                let val = self.rdn();
+                self.stc(val)?;
+            }
+            b'D' => {
+                self.rch();
+                if self.ch == b'L' as Word {
+                    self.rch();
+                    self.stw(0)?;
+                    let n = self.rdlabel()?;
+                    let addr = self.lomem - 1;
+                    self.labref(n, addr)?;
+                } else {
+                    let val = self.rdn();
+                    self.stw(val)?;
+                }
+            }
+            b'G' => {
+                self.rch();
+                let n = self.rdn();
+                if self.ch != b'L' as Word {
+                    return Err(VmError::BadCode { at: Context::default() });
+                }
+                self.rch();
+                if n < 0 || n as usize >= self.progstart {
+                    return Err(VmError::BadGlobal { global: n, at: Context::default() });
+                }
+                self.store(n as UWord as usize, 0)?;
+                self.entry_globals[n as usize] = true;
+                self.assigned[n as usize] = true;
+                let lab = self.rdlabel()?;
+                self.labref(lab, n as UWord as usize)?;
+            }
+            b'Z' => {
+                let unset: Vec<_> = (self.src.labels.iter().enumerate())
+                    .filter_map(|(n, label)| match label {
+                        Label::Unset { first, .. } => Some((n, *first)),
+                        _ => None,
+                    })
+                    .collect();
+                for (n, first) in unset {
+                    let e = VmError::UnsetLabel { label: n as Word, at: Context::default() };
+                    self.src.diagnose(e.at(self.lomem, 0), first);
+                }
+                // Stop at the end of a section with errors, reading the
+                // rest of the line so excerpts are complete.
+                if !self.src.diagnostics.is_empty() {
+                    self.skip_line();
+                    return Ok(false);
+                }
+                // Restart with no labels
+                self.cp = 0;
+                self.rch();
+                self.list_section(false);
+                self.keep_labels();
+            }
+            _ => {
+                if self.ch == ENDSTREAMCH {
+                    return Ok(false);
+                }
+                return Err(VmError::BadCh { ch: self.ch, at: Context::default() });
+            }
+        }
+        Ok(true)
+    }
+
+    fn process_instruction(&mut self, n: Word) -> Result<(), VmError> {
+        let start = self.lomem;
+        let result = self.instruction(n);
+        self.mark_code(start);
+        result
+    }
+
+    fn instruction(&mut self, mut n: Word) -> Result<(), VmError> {
+        self.rch();
+        if self.ch == b'I' as Word {
+            n |= FI_BIT;
+            self.rch();
+        }
+        if self.ch == b'P' as Word {
+            n |= FP_BIT;
+            self.rch();
+        }
+        if self.ch == b'G' as Word {
+            self.rch();
+        }
+
+        if self.ch == b'L' as Word {
+            self.rch();
+            self.stw(n | FD_BIT)?;
+            self.stw(0)?;
+            let lab = self.rdlabel()?;
+            let addr = self.lomem - 1;
+            self.labref(lab, addr)
+        } else {
+            let d = self.rdn();
+            if (d & FN_MASK) == d {
+                self.stw(n | (d << FN_BITS))
+            } else {
+                self.stw(n | FD_BIT)?;
+                self.stw(d)
+            }
+        }
+    }
+
+    fn stw(&mut self, w: Word) -> Result<(), VmError> {
+        self.store(self.lomem, w)?;
+        self.src.placed(self.lomem);
+        self.lomem += 1;
+        self.cp = 0;
+        Ok(())
+    }
+
+    fn stc(&mut self, c: Word) -> Result<(), VmError> {
+        if self.cp == 0 {
+            self.stw(0)?;
+        } else {
+            self.src.placed(self.lomem - 1);
+        }
+        let byte_addr = (self.lomem - 1) * BYTESPERWORD + self.cp;
+        self.set_byte(byte_addr, c as u8)?;
+        self.cp += 1;
+        if self.cp == BYTESPERWORD {
+            self.cp = 0;
+        }
+        Ok(())
+    }
+
+    // Like rdch, but counts lines and columns and keeps the text of each
+    // line. CR, LF and CRLF all end a line.
+    fn src_rdch(&mut self) -> Word {
+        let c = match self.files.get_mut(self.cis) {
+            Some(Some(input)) => input.read_byte(),
+            _ => None,
+        };
+        let src = &mut self.src;
+        let c = match c {
+            None => return ENDSTREAMCH,
+            Some(ASC_LF) if src.after_cr => {
+                src.after_cr = false;
+                return self.src_rdch();
+            }
+            Some(c) => c,
+        };
+        src.after_cr = c == ASC_CR;
+        if src.at_line_start {
+            src.line += 1;
+            src.col = 0;
+            src.lines.push(String::new());
+            src.at_line_start = false;
+        }
+        src.col += 1;
+        if c == ASC_CR || c == ASC_LF {
+            src.at_line_start = true;
+            ASC_LF as Word
+        } else {
+            if let Some(text) = src.lines.last_mut() {
+                text.push(c as char);
+            }
+            c as Word
+        }
+    }
+
+    fn rch(&mut self) {
+        self.ch = self.src_rdch();
+        while self.ch == ASC_SLASH as Word {
+            loop {
+                self.ch = self.src_rdch();
+                if self.ch == ASC_LF as Word || self.ch == ENDSTREAMCH {
+                    break;
+                }
+            }
+            while self.ch == ASC_LF as Word {
+                self.ch = self.src_rdch();
+            }
+        }
+    }
+
+    // Error recovery: drop the rest of the current line.
+    fn skip_line(&mut self) {
+        while self.ch != ASC_LF as Word && self.ch != ENDSTREAMCH {
+            self.ch = self.src_rdch();
+        }
+    }
+
+    fn rdn(&mut self) -> Word {
+        let mut sum: Word = 0;
+        let mut digits = String::new();
+        let start = self.src.here();
+        let neg = self.ch == ASC_MINUS as Word;
+        if neg {
+            self.rch();
+        }
+        while self.ch >= ASC_0 as Word && self.ch <= ASC_9 as Word {
+            sum = sum.wrapping_mul(10).wrapping_add(self.ch - ASC_0 as Word);
+            digits.push(self.ch as u8 as char);
+            self.rch();
+        }
+        if !fits_word(&digits, neg) {
+            let sign = if neg { "-" } else { "" };
+            self.number_overflow(format!("READING {}{}", sign, digits), start);
+        }
+        if neg { sum.wrapping_neg() } else { sum }
+    }
+
+    // A number in the INTCODE that does not fit in a word.
+    #[cold]
+    fn number_overflow(&mut self, expr: String, (line, column): (usize, usize)) {
+        let err = VmError::Overflow { expr, at: Context { pc: self.lomem, sp: 0 } };
+        match self.overflow_check {
+            OverflowCheck::Off => {}
+            OverflowCheck::Warn => {
+                let text = format!("{}:{}:{}: WARNING {}", self.src.name, line, column, err);
+                self.warn(&text);
+            }
+            OverflowCheck::Stop => self.src.diagnose(err, (line, column)),
+        }
+    }
+
+    // Moves the labels of a finished section to `section_labels`.
+    fn keep_labels(&mut self) {
+        let labels = std::mem::take(&mut self.src.labels);
+        let defined = (labels.into_iter().enumerate())
+            .filter_map(|(n, label)| match label {
+                Label::Defined(addr) => Some((n, addr)),
+                _ => None,
+            })
+            .collect();
+        self.section_labels.push(defined);
+    }
+
+    /// Addresses of label `n` in each section loaded so far, for tools that
+    /// refer to code by its INTCODE labels.
+    pub fn label_addresses(&self, n: usize) -> Vec<usize> {
+        (self.section_labels.iter())
+            .filter_map(|labels| labels.iter().find(|&&(l, _)| l == n))
+            .map(|&(_, addr)| addr)
+            .collect()
+    }
+
+    // Reads a label number, making room for it in the label table.
+    fn rdlabel(&mut self) -> Result<usize, VmError> {
+        let mut n = 0usize;
+        while self.ch >= ASC_0 as Word && self.ch <= ASC_9 as Word {
+            n = n.saturating_mul(10).saturating_add((self.ch - ASC_0 as Word) as usize);
+            self.rch();
+        }
+        if n > MAX_LABEL {
+            return Err(VmError::BadLabel { label: n, at: Context::default() });
+        }
+        if n >= self.src.labels.len() {
+            self.src.labels.resize(n + 1, Label::Unused);
+        }
+        Ok(n)
+    }
+
+    fn labref(&mut self, n: usize, a: usize) -> Result<(), VmError> {
+        match &mut self.src.labels[n] {
+            Label::Defined(addr) => {
+                let addr = *addr as Word;
+                let w = self.load(a)?;
+                self.store(a, w.wrapping_add(addr))
+            }
+            Label::Unset { refs, .. } => {
+                refs.push(a);
+                Ok(())
+            }
+            label @ Label::Unused => {
+                *label = Label::Unset {
+                    refs: vec![a],
+                    first: self.src.start,
+                };
+                Ok(())
+            }
+        }
+    }
+}
+
+// Words shown on each row of the listing
+const LISTING_WORDS: usize = 8;
+
+impl BcplState {
+    // Writes the listing for the lines read since the last call, with the
+    // words as they are now that the section's labels are resolved, then
+    // the section's label table. The last line is left for next time unless
+    // it is complete or `all` is set.
+    fn list_section(&mut self, all: bool) {
+        let Some(out) = self.listing.as_mut() else {
+            return;
+        };
+        let src = &self.src;
+        let mut end = src.lines.len();
+        if !all && !src.at_line_start {
+            end = end.saturating_sub(1);
+        }
+
+        let mut text = String::new();
+        if src.listed == 0 {
+            text.push_str(&format!("ICFILE {}\n LINE  ADDR  WORDS\n", src.name));
+        }
+        for i in src.listed..end {
+            let words: Vec<String> = match src.words.get(i).copied().flatten() {
+                Some((from, to)) => (from..to)
+                    .map(|a| format!("{:01$X}", self.m.get(a).copied().unwrap_or(0) as UWord, 2 * BYTESPERWORD))
+                    .collect(),
+                None => Vec::new(),
+            };
+            let addr = match src.words.get(i).copied().flatten() {
+                Some((from, _)) => format!("{:5}", from),
+                None => " ".repeat(5),
+            };
+            let mut rows = words.chunks(LISTING_WORDS);
+            let first = rows.next().map(|w| w.join(" ")).unwrap_or_default();
+            text.push_str(&format!(
+                "{:5} {}  {:<width$}  {}\n",
+                i + 1,
+                addr,
+                first,
+                src.lines[i],
+                width = LISTING_WORDS * (2 * BYTESPERWORD + 1) - 1
+            ));
+            for row in rows {
+                text.push_str(&format!("{:13}{}\n", "", row.join(" ")));
+            }
+        }
+
+        let defined: Vec<String> = (src.labels.iter().enumerate())
+            .filter_map(|(n, label)| match label {
+                Label::Defined(addr) => Some(format!("L{}={}", n, addr)),
+                _ => None,
+            })
+            .collect();
+        if !defined.is_empty() {
+            text.push_str("LABELS\n");
+            for row in defined.chunks(LISTING_WORDS) {
+                text.push_str(&format!("    {}\n", row.join(" ")));
+            }
+        }
+
+        out.write_bytes(text.as_bytes());
+        out.flush();
+        self.src.listed = end;
+    }
+}
@@ -0,0 +1,83 @@
+// Stack backtraces. A K instruction that enters a procedure leaves the
+// caller's SP in m[sp] and the return address in m[sp+1] of the new frame,
+// so the frames form a chain down to the start-up frame at LOMEM.
+
+use crate::*;
+
+/// Words shown for each frame after its link words. The frame does not
+/// record how many arguments the call passed, so these are the first
+/// arguments if there are that many, and locals or leftovers otherwise.
+const BACKTRACE_WORDS: usize = 3;
+/// Frames shown before the rest of a deep stack is left out.
+const BACKTRACE_DEPTH: usize = 50;
+
+/// One procedure activation on the stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Frame base: the previous SP is at `sp`, the return address at `sp + 1`
+    /// and the arguments, then the locals, from `sp + 2`.
+    pub sp: UWord,
+    /// Where execution is in this frame: the faulting or current
+    /// instruction for the innermost frame, the return address for the rest.
+    pub pc: UWord,
+    /// The first few words after the link words.
+    pub words: Vec<Word>,
+}
+
+impl BcplState {
+    /// The chain of frames from the given registers outward, innermost
+    /// first. The walk stops at a link that does not lead to a lower frame
+    /// in memory or does not return into the code area.
+    pub fn backtrace_from(&self, pc: UWord, sp: UWord) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let (mut pc, mut sp) = (pc, sp);
+        loop {
+            let base = sp as usize;
+            if base + 1 >= self.m.len() {
+                break;
+            }
+            let words = (base + 2..base + 2 + BACKTRACE_WORDS)
+                .filter_map(|i| self.m.get(i).copied())
+                .collect();
+            frames.push(Frame { sp, pc, words });
+
+            let (prev, ret) = (self.m[base] as UWord, self.m[base + 1] as UWord);
+            if prev >= sp || (ret as usize) < self.progstart || ret as usize >= self.m.len() {
+                break;
+            }
+            (pc, sp) = (ret, prev);
+        }
+        frames
+    }
+
+    /// The backtrace from the current registers.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        self.backtrace_from(self.regs.pc, self.regs.sp)
+    }
+
+    /// The backtrace as text, one line per frame. With symbols loaded each
+    /// frame also names the procedure it is in.
+    pub fn format_backtrace(&self, frames: &[Frame]) -> String {
+        let symbols = self.has_symbols();
+        let heading = if symbols {
+            "    #  FRAME     PC  PROCEDURE         WORDS"
+        } else {
+            "    #  FRAME     PC  WORDS"
+        };
+        let mut lines = vec!["BACKTRACE".to_string(), heading.to_string()];
+        for (i, frame) in frames.iter().take(BACKTRACE_DEPTH).enumerate() {
+            let words: Vec<String> = frame.words.iter().map(|w| w.to_string()).collect();
+            let mut line = format!("{:5}  {:5}  {:5}  ", i, frame.sp, frame.pc);
+            if symbols {
+                let name = self.procedure_at(frame.pc).unwrap_or_else(|| "?".to_string());
+                line.push_str(&format!("{:<16}  ", name));
+            }
+            line.push_str(&words.join(" "));
+            lines.push(line);
+        }
+        if frames.len() > BACKTRACE_DEPTH {
+            lines.push(format!("  ... {} MORE", frames.len() - BACKTRACE_DEPTH));
+        }
+        lines.join("\n")
+    }
+}
@@ -0,0 +1,49 @@
+// Memory geometry of the machine. The defaults are the classic icint values;
+// larger programs can ask for more store or a bigger global vector.
+
+use crate::{K90_CHANGECO, PROGSTART, VmError, WORDCOUNT};
+
+/// Largest store addressable with 16-bit words.
+#[cfg(not(feature = "word32"))]
+pub const MAX_WORDCOUNT: usize = 1 << 16;
+/// Largest store allowed with 32-bit words (64 MB).
+#[cfg(feature = "word32")]
+pub const MAX_WORDCOUNT: usize = 1 << 24;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Total words of memory.
+    pub wordcount: usize,
+    /// Address of the first word of code; globals are `0..progstart`.
+    pub progstart: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            wordcount: WORDCOUNT,
+            progstart: PROGSTART,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), VmError> {
+        let bad = |msg: String| Err(VmError::BadConfig(msg));
+        if self.wordcount > MAX_WORDCOUNT {
+            return bad(format!("WORDCOUNT {} ABOVE {}", self.wordcount, MAX_WORDCOUNT));
+        }
+        if self.progstart <= K90_CHANGECO as usize {
+            return bad(format!("PROGSTART {} NOT ABOVE THE K-CODES", self.progstart));
+        }
+        // Globals and the three start-up words must fit with room left for
+        // code and stack.
+        if self.progstart + 3 >= self.wordcount {
+            return bad(format!(
+                "WORDCOUNT {} TOO SMALL FOR PROGSTART {}",
+                self.wordcount, self.progstart
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+// Interactive debugger behind `icint --debug`. Commands are read a line at
+// a time and the program is driven one instruction at a time with
+// `BcplState::step`, so the registers can be shown between instructions.
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use crate::*;
+
+const HELP: &str = "\
+s [N]          step N instructions (default 1)
+n              step over a procedure call
+c              continue to a breakpoint or the end
+b [WHERE]      set a breakpoint, or list them
+d WHERE        delete a breakpoint
+r              show registers and the instruction at PC
+t              show the stack backtrace
+x WHERE [N]    show N words of memory (default 8)
+g N [COUNT]    show COUNT globals from GN (default 1)
+l [WHERE [N]]  decode N instructions (default 5 from PC)
+q              quit
+WHERE is an address, Ln for INTCODE label n, or Gn or a header name
+(with --symbols) for the procedure in that global. N in g may be a name.";
+
+/// Command-driven debugger for a loaded program.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    // How the program ended, once it has
+    ended: Option<Result<Word, VmError>>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Runs the loaded program from the start under the commands read from
+    /// `input`, writing to `out`. Returns the stop code, or the fault that
+    /// ended the program, or `None` if the user quit first.
+    pub fn run(
+        &mut self,
+        state: &mut BcplState,
+        input: impl BufRead,
+        out: impl Write,
+    ) -> Result<Option<Word>, VmError> {
+        state.reset_registers();
+        self.resume(state, input, out)
+    }
+
+    /// As `run`, but from the current registers, such as those of a
+    /// restored snapshot.
+    pub fn resume(
+        &mut self,
+        state: &mut BcplState,
+        mut input: impl BufRead,
+        mut out: impl Write,
+    ) -> Result<Option<Word>, VmError> {
+        self.ended = None;
+        self.show(state, &mut out);
+
+        let mut line = String::new();
+        loop {
+            state.flush();
+            let _ = write!(out, "* ");
+            let _ = out.flush();
+            line.clear();
+            if input.read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((&cmd, args)) = words.split_first() else {
+                continue;
+            };
+            if cmd == "q" || cmd == "quit" {
+                break;
+            }
+            if let Err(msg) = self.command(state, cmd, args, &mut out) {
+                let _ = writeln!(out, "{}", msg);
+            }
+        }
+        self.ended.take().transpose()
+    }
+
+    fn command(
+        &mut self,
+        state: &mut BcplState,
+        cmd: &str,
+        args: &[&str],
+        out: &mut impl Write,
+    ) -> Result<(), String> {
+        match cmd {
+            "s" | "step" => {
+                let n = count(args.first(), 1)?;
+                for _ in 0..n {
+                    if !self.step(state, out)? {
+                        return Ok(());
+                    }
+                }
+                self.show(state, out);
+            }
+            "n" | "next" => {
+                let r = state.registers();
+                let ins = state.instruction_at(r.pc as usize).ok_or("BAD PC")?;
+                if ins.op == F6_K && r.a as UWord as usize >= state.progstart() {
+                    // The call is over when control is back after the K
+                    // instruction with the caller's stack pointer.
+                    let (ret, sp) = (r.pc as usize + ins.words(), r.sp);
+                    if self.step(state, out)? {
+                        self.run_until(state, out, |r| r.pc as usize == ret && r.sp == sp)?;
+                    }
+                } else if self.step(state, out)? {
+                    self.show(state, out);
+                }
+            }
+            "c" | "continue" => {
+                self.step(state, out)?;
+                self.run_until(state, out, |_| false)?;
+            }
+            "b" | "break" => {
+                if args.is_empty() {
+                    for addr in &self.breakpoints {
+                        let _ = writeln!(out, "{}", addr);
+                    }
+                }
+                for arg in args {
+                    let addrs = where_(state, arg)?;
+                    // Below PROGSTART are globals, and a global naming a
+                    // K-code holds its number
+                    if let Some(addr) = addrs.iter().find(|&&a| a < state.progstart()) {
+                        return Err(format!("NO CODE AT {}", addr));
+                    }
+                    for addr in addrs {
+                        self.breakpoints.insert(addr);
+                        let _ = writeln!(out, "BREAKPOINT AT {}", addr);
+                    }
+                }
+            }
+            "d" | "delete" => {
+                for arg in args {
+                    for addr in where_(state, arg)? {
+                        if !self.breakpoints.remove(&addr) {
+                            return Err(format!("NO BREAKPOINT AT {}", addr));
+                        }
+                    }
+                }
+            }
+            "r" | "regs" => self.show(state, out),
+            "t" | "bt" => {
+                let _ = writeln!(out, "{}", state.format_backtrace(&state.backtrace()));
+            }
+            "x" | "mem" => {
+                let arg = args.first().ok_or("WHERE?")?;
+                let addr = single(where_(state, arg)?)?;
+                let n = count(args.get(1), 8)?;
+                let m = state.memory();
+                let end = addr.saturating_add(n).min(m.len());
+                let words = m.get(addr..end).ok_or("BAD ADDRESS")?;
+                for (i, row) in words.chunks(8).enumerate() {
+                    let _ = write!(out, "{:5}:", addr + 8 * i);
+                    for w in row {
+                        let _ = write!(out, " {:6}", w);
+                    }
+                    let _ = writeln!(out);
+                }
+            }
+            "g" | "global" => {
+                let first = match args.first().and_then(|a| state.global_named(a)) {
+                    Some(g) => g,
+                    None => count(args.first(), usize::MAX)?,
+                };
+                if first >= state.progstart() {
+                    return Err("NO SUCH GLOBAL".to_string());
+                }
+                let n = count(args.get(1), 1)?;
+                for g in first..first.saturating_add(n).min(state.progstart()) {
+                    let _ = writeln!(out, "{} = {}", state.global_label(g), state.global(g));
+                }
+            }
+            "l" | "list" => {
+                let mut addr = match args.first() {
+                    Some(arg) => single(where_(state, arg)?)?,
+                    None => state.registers().pc as usize,
+                };
+                let pc = state.registers().pc as usize;
+                for _ in 0..count(args.get(1), 5)? {
+                    let Some(ins) = state.instruction_at(addr) else {
+                        break;
+                    };
+                    let _ = writeln!(out, "{:5}: {}", addr, describe(state, &ins, addr == pc));
+                    addr += ins.words();
+                }
+            }
+            "h" | "help" | "?" => {
+                let _ = writeln!(out, "{}", HELP);
+            }
+            _ => return Err(format!("UNKNOWN COMMAND {} (h FOR HELP)", cmd)),
+        }
+        Ok(())
+    }
+
+    // Executes one instruction. Returns false, having reported it, if the
+    // program has ended.
+    fn step(&mut self, state: &mut BcplState, out: &mut impl Write) -> Result<bool, String> {
+        if self.ended.is_some() {
+            return Err("PROGRAM HAS ENDED".to_string());
+        }
+        match state.step() {
+            Ok(None) => return Ok(true),
+            Ok(Some(code)) => {
+                state.flush();
+                let _ = writeln!(out, "STOPPED WITH {}", code);
+                self.ended = Some(Ok(code));
+            }
+            Err(e) => {
+                state.flush();
+                let _ = writeln!(out, "{}", e);
+                self.show(state, out);
+                self.ended = Some(Err(e));
+            }
+        }
+        Ok(false)
+    }
+
+    // Steps until `done` holds, a breakpoint is reached or the program ends.
+    fn run_until(
+        &mut self,
+        state: &mut BcplState,
+        out: &mut impl Write,
+        done: impl Fn(&Regs) -> bool,
+    ) -> Result<(), String> {
+        if self.ended.is_some() {
+            return Ok(());
+        }
+        loop {
+            let r = state.registers();
+            if done(&r) {
+                break;
+            }
+            if self.breakpoints.contains(&(r.pc as usize)) {
+                let _ = writeln!(out, "BREAKPOINT");
+                break;
+            }
+            if !self.step(state, out)? {
+                return Ok(());
+            }
+        }
+        self.show(state, out);
+        Ok(())
+    }
+
+    fn show(&self, state: &BcplState, out: &mut impl Write) {
+        let r = state.registers();
+        let ins = match state.instruction_at(r.pc as usize) {
+            Some(ins) => describe(state, &ins, true),
+            None => "?".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "PC {:5}  SP {:5}  A {:6}  B {:6}   {}",
+            r.pc, r.sp, r.a, r.b, ins
+        );
+    }
+}
+
+// Instruction text with what it does where that is not obvious, and the
+// header name of the global it uses. The target of a call is only known
+// for the instruction at PC.
+fn describe(state: &BcplState, ins: &Instruction, at_pc: bool) -> String {
+    let a = state.registers().a;
+    let note = match ins.op {
+        F7_X => xcode_name(ins.d).map(String::from),
+        F6_K if at_pc && (a as UWord as usize) < state.progstart() => {
+            Some(format!("K-CODE {}", a))
+        }
+        F6_K if at_pc => Some(format!("CALL {}", a as UWord)),
+        _ => state.operand_name(ins).map(String::from),
+    };
+    let callee = if ins.op == F6_K && at_pc { state.callee_name(a) } else { None };
+    match (note, callee) {
+        (Some(note), Some(name)) => format!("{:<10} {} {}", ins.to_string(), note, name),
+        (Some(note), None) => format!("{:<10} {}", ins.to_string(), note),
+        (None, _) => ins.to_string(),
+    }
+}
+
+// Addresses named by WHERE: a number, Ln, Gn or the header name of a
+// global.
+fn where_(state: &BcplState, arg: &str) -> Result<Vec<usize>, String> {
+    let bad = || format!("BAD ADDRESS {}", arg);
+    let number = |s: &str| s.parse::<usize>().map_err(|_| bad());
+    let addrs = if let Some(n) = arg.strip_prefix(['L', 'l']) {
+        let addrs = state.label_addresses(number(n)?);
+        if addrs.is_empty() {
+            return Err(format!("NO LABEL {}", arg));
+        }
+        addrs
+    } else if let Some(n) = arg.strip_prefix(['G', 'g']).and_then(|n| n.parse::<usize>().ok()) {
+        if n >= state.progstart() {
+            return Err("NO SUCH GLOBAL".to_string());
+        }
+        vec![state.global(n) as UWord as usize]
+    } else if let Some(g) = state.global_named(arg) {
+        vec![state.global(g) as UWord as usize]
+    } else {
+        vec![number(arg)?]
+    };
+    if addrs.iter().any(|&a| a >= state.memory().len()) {
+        return Err(bad());
+    }
+    Ok(addrs)
+}
+
+fn single(addrs: Vec<usize>) -> Result<usize, String> {
+    match addrs[..] {
+        [addr] => Ok(addr),
+        _ => Err("LABEL IS IN SEVERAL SECTIONS; GIVE AN ADDRESS".to_string()),
+    }
+}
+
+fn count(arg: Option<&&str>, default: usize) -> Result<usize, String> {
+    match arg {
+        Some(s) => s.parse().map_err(|_| format!("BAD NUMBER {}", s)),
+        None if default == usize::MAX => Err("NUMBER?".to_string()),
+        None => Ok(default),
+    }
+}
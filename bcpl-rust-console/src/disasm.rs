@@ -0,0 +1,459 @@
+// INTCODE disassembler. Decodes single instructions for diagnostics and
+// turns the loaded code area back into INTCODE text: code is found by
+// following control flow from the start-up code and the global procedure
+// entries, SWITCHON tables and C-packed strings are recognised from the way
+// cgi lays them out, and everything else is shown as D words.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+use crate::*;
+
+// Words of start-up code (LIG1 K2 X22) that `init` puts at PROGSTART.
+const STARTUP_WORDS: usize = 3;
+
+/// One decoded INTCODE instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    /// Function code, `F0_L` to `F7_X`.
+    pub op: Word,
+    pub indirect: bool,
+    /// `d` is relative to the stack pointer.
+    pub p: bool,
+    /// `d` is held in the word after the instruction.
+    pub long: bool,
+    pub d: Word,
+}
+
+impl Instruction {
+    /// Decodes the instruction word `w`; `next` is the word after it, used
+    /// when the operand does not fit in the instruction.
+    pub fn decode(w: Word, next: Word) -> Self {
+        let long = w & FD_BIT != 0;
+        Instruction {
+            op: w & F7_X,
+            indirect: w & FI_BIT != 0,
+            p: w & FP_BIT != 0,
+            long,
+            d: if long { next } else { (w as UWord >> FN_BITS) as Word },
+        }
+    }
+
+    /// Number of words the instruction occupies.
+    pub fn words(&self) -> usize {
+        if self.long { 2 } else { 1 }
+    }
+
+    pub fn letter(&self) -> char {
+        b"LSAJTFKX"[self.op as usize] as char
+    }
+
+    // Function letter and modifiers, e.g. "LIP" or "SG".
+    fn prefix(&self) -> String {
+        let mut s = String::from(self.letter());
+        if self.indirect {
+            s.push('I');
+        }
+        if self.p {
+            s.push('P');
+        } else if self.indirect || self.op == F1_S {
+            s.push('G');
+        }
+        s
+    }
+}
+
+// As cgi writes it: "LIP2", "SG5", "X23".
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.prefix(), self.d)
+    }
+}
+
+/// Name of the operation performed by `X n`.
+pub fn xcode_name(n: Word) -> Option<&'static str> {
+    const NAMES: [&str; 23] = [
+        "RV", "NEG", "NOT", "RTN", "MULT", "DIV", "REM", "PLUS", "MINUS", "EQ", "NE", "LS", "GE",
+        "GR", "LE", "LSHIFT", "RSHIFT", "LOGAND", "LOGOR", "NEQV", "EQV", "FINISH", "SWITCHON",
+    ];
+    NAMES.get((n as usize).wrapping_sub(1)).copied()
+}
+
+// Whether the assembler could have produced `w` as an instruction word.
+// Bits 6 and 7 are never set, and a long instruction has its operand in
+// the next word, so nothing above the modifier bits.
+fn assembled(w: Word) -> bool {
+    let w = w as UWord;
+    let operand = if w & FD_BIT as UWord != 0 { 0 } else { FN_MASK as UWord };
+    w & !(0x3F | operand << FN_BITS) == 0
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Unknown,
+    Code,
+    // Second word of a long instruction
+    Operand,
+    Data,
+    Text,
+    Table,
+}
+
+struct Disassembler<'a> {
+    m: &'a [Word],
+    start: usize,
+    end: usize,
+    kinds: Vec<Kind>,
+    // Label number of each referenced address, assigned in address order
+    labels: BTreeMap<usize, usize>,
+    // Addresses loaded with L whose use is not yet known
+    loaded: BTreeSet<usize>,
+    // Loaded addresses that are called straight away
+    called: BTreeSet<usize>,
+    data: BTreeSet<usize>,
+    // Start address and length in words
+    texts: BTreeMap<usize, usize>,
+    tables: BTreeMap<usize, usize>,
+}
+
+impl<'a> Disassembler<'a> {
+    fn new(m: &'a [Word], start: usize, end: usize) -> Self {
+        Disassembler {
+            m,
+            start,
+            end,
+            kinds: vec![Kind::Unknown; end.saturating_sub(start)],
+            labels: BTreeMap::new(),
+            loaded: BTreeSet::new(),
+            called: BTreeSet::new(),
+            data: BTreeSet::new(),
+            texts: BTreeMap::new(),
+            tables: BTreeMap::new(),
+        }
+    }
+
+    fn in_range(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    fn kind(&self, addr: usize) -> Kind {
+        self.kinds[addr - self.start]
+    }
+
+    fn mark(&mut self, addr: usize, words: usize, kind: Kind) {
+        for a in addr..(addr + words).min(self.end) {
+            self.kinds[a - self.start] = kind;
+        }
+    }
+
+    fn word(&self, addr: usize) -> Word {
+        self.m.get(addr).copied().unwrap_or(0)
+    }
+
+    fn instruction(&self, addr: usize) -> Instruction {
+        Instruction::decode(self.word(addr), self.word(addr + 1))
+    }
+
+    // Address named by a long operand that may have assembled from a label.
+    // Additions, calls and X codes take plain numbers.
+    fn target(&self, ins: &Instruction) -> Option<usize> {
+        let addr = ins.d as UWord as usize;
+        let numeric = !ins.indirect && ins.op == F2_A || ins.op == F6_K || ins.op == F7_X;
+        let in_program = addr >= self.start + STARTUP_WORDS && addr < self.end;
+        (ins.long && !ins.p && !numeric && in_program).then_some(addr)
+    }
+
+    fn analyse(&mut self, globals: &[Word]) {
+        let mut roots = vec![self.start];
+        roots.extend(
+            globals
+                .iter()
+                .map(|&g| g as UWord as usize)
+                .filter(|&a| self.in_range(a)),
+        );
+        for &a in &roots[1..] {
+            self.labels.insert(a, 0);
+        }
+
+        loop {
+            self.trace(roots);
+            roots = Vec::new();
+
+            // Addresses only ever loaded: a procedure if it is called, a
+            // string if it looks like one, otherwise data.
+            for addr in std::mem::take(&mut self.loaded) {
+                if self.kind(addr) != Kind::Unknown {
+                    continue;
+                }
+                if self.called.contains(&addr) {
+                    roots.push(addr);
+                } else if let Some(words) = self.text_len(addr) {
+                    self.texts.insert(addr, words);
+                    self.mark(addr, words, Kind::Text);
+                } else {
+                    self.data.insert(addr);
+                }
+            }
+
+            // Anything left is code nothing jumps to, such as the jump at
+            // the head of each section, or data from a referenced address up
+            // to the next label.
+            if roots.is_empty()
+                && let Some(i) = self.kinds.iter().position(|&k| k == Kind::Unknown)
+            {
+                let addr = self.start + i;
+                if self.data.contains(&addr) {
+                    let mut end = addr + 1;
+                    while self.in_range(end)
+                        && self.kind(end) == Kind::Unknown
+                        && !self.labels.contains_key(&end)
+                    {
+                        end += 1;
+                    }
+                    self.mark(addr, end - addr, Kind::Data);
+                    continue;
+                }
+                roots.push(addr);
+            }
+            if roots.is_empty() {
+                break;
+            }
+        }
+    }
+
+    fn trace(&mut self, mut work: Vec<usize>) {
+        while let Some(mut addr) = work.pop() {
+            while self.in_range(addr) && self.kind(addr) == Kind::Unknown {
+                let ins = self.instruction(addr);
+                if !assembled(self.word(addr))
+                    || addr + ins.words() > self.end
+                    || ins.long && self.kind(addr + 1) != Kind::Unknown
+                {
+                    self.mark(addr, 1, Kind::Data);
+                    break;
+                }
+                self.mark(addr, 1, Kind::Code);
+                if ins.long {
+                    self.mark(addr + 1, 1, Kind::Operand);
+                }
+                let next = addr + ins.words();
+
+                if let Some(target) = self.target(&ins) {
+                    self.labels.insert(target, 0);
+                    match ins.op {
+                        F3_J | F4_T | F5_F if !ins.indirect => work.push(target),
+                        F0_L if !ins.indirect => {
+                            self.loaded.insert(target);
+                            if self.instruction(next).op == F6_K {
+                                self.called.insert(target);
+                            }
+                        }
+                        _ => {
+                            self.data.insert(target);
+                        }
+                    }
+                }
+
+                match ins.op {
+                    F3_J => break,
+                    F7_X if ins.d == 4 || ins.d == 22 => break,
+                    F7_X if ins.d == 23 => {
+                        work.extend(self.table(next));
+                        break;
+                    }
+                    _ => addr = next,
+                }
+            }
+        }
+    }
+
+    // Marks the SWITCHON table at `addr` (case count, default label, then
+    // value/label pairs) and returns the labels.
+    fn table(&mut self, addr: usize) -> Vec<usize> {
+        let count = self.word(addr).max(0) as usize;
+        let words = (2 + 2 * count).min(self.end.saturating_sub(addr));
+        self.tables.insert(addr, words);
+        self.mark(addr, words, Kind::Table);
+        let mut targets = vec![self.word(addr + 1) as UWord as usize];
+        targets.extend((0..count).map(|i| self.word(addr + 3 + 2 * i) as UWord as usize));
+        targets.retain(|&t| self.in_range(t));
+        for &t in &targets {
+            self.labels.insert(t, 0);
+        }
+        targets
+    }
+
+    // Words taken by a plausible BCPL string at `addr`.
+    fn text_len(&self, addr: usize) -> Option<usize> {
+        let byte = |i: usize| {
+            let w = self.word(addr + i / BYTESPERWORD) as UWord;
+            w.to_le_bytes()[i % BYTESPERWORD]
+        };
+        let len = byte(0) as usize;
+        let words = (len + 1).div_ceil(BYTESPERWORD);
+        if len == 0 || addr + words > self.end {
+            return None;
+        }
+        let printable = (1..=len).all(|i| matches!(byte(i), 7..=13 | 32..=126));
+        let padded = (len + 1..words * BYTESPERWORD).all(|i| byte(i) == 0);
+        let free = (addr..addr + words).all(|a| self.kind(a) == Kind::Unknown);
+        (printable && padded && free).then_some(words)
+    }
+
+    fn label(&self, addr: usize) -> String {
+        format!("L{}", self.labels[&addr])
+    }
+
+    fn line(&self, out: &mut String, text: &str, addr: usize, words: usize, note: &str) {
+        let mut comment = format!("/ {:5}:", addr);
+        for a in addr..addr + words {
+            let _ = write!(comment, " {:01$X}", self.word(a) as UWord, 2 * BYTESPERWORD);
+        }
+        if !note.is_empty() {
+            comment.push_str("  ");
+            comment.push_str(note);
+        }
+        let _ = writeln!(out, "    {:<19} {}", text, comment);
+    }
+
+    fn listing(&mut self, globals: &[Word]) -> String {
+        // Only addresses that start a line can carry a label; operands that
+        // point elsewhere are shown as numbers.
+        let kinds = std::mem::take(&mut self.kinds);
+        let start = self.start;
+        self.labels.retain(|&a, _| match kinds[a - start] {
+            Kind::Code | Kind::Data => true,
+            Kind::Text => self.texts.contains_key(&a),
+            Kind::Table => self.tables.contains_key(&a),
+            Kind::Unknown | Kind::Operand => false,
+        });
+        self.kinds = kinds;
+        for (n, label) in self.labels.values_mut().enumerate() {
+            *label = n + 1;
+        }
+
+        let mut out = String::new();
+        let mut addr = self.start;
+        while addr < self.end {
+            if let Some(n) = self.labels.get(&addr) {
+                let _ = writeln!(out, "{}", n);
+            }
+            // The start-up code is not part of the program, so is commented
+            // out to keep the listing assemblable.
+            if addr < self.start + STARTUP_WORDS {
+                out.push('/');
+            }
+            addr += match self.kind(addr) {
+                Kind::Code => self.code(&mut out, addr),
+                Kind::Text if self.texts.contains_key(&addr) => self.text(&mut out, addr),
+                Kind::Table if self.tables.contains_key(&addr) => self.switch(&mut out, addr),
+                _ => {
+                    self.line(&mut out, &self.data_word(addr), addr, 1, "");
+                    1
+                }
+            };
+        }
+
+        for (g, &value) in globals.iter().enumerate() {
+            let addr = value as UWord as usize;
+            if self.in_range(addr) {
+                let _ = writeln!(out, "G{}{}", g, self.label(addr));
+            }
+        }
+        out.push('Z');
+        out
+    }
+
+    fn code(&self, out: &mut String, addr: usize) -> usize {
+        let ins = self.instruction(addr);
+        let text = match self.target(&ins) {
+            Some(t) if self.labels.contains_key(&t) => {
+                let mut s = ins.prefix();
+                if s.ends_with('G') {
+                    s.pop();
+                }
+                s + &self.label(t)
+            }
+            _ => ins.to_string(),
+        };
+        let note = match ins.op {
+            F7_X => xcode_name(ins.d).unwrap_or(""),
+            _ => "",
+        };
+        self.line(out, &text, addr, ins.words(), note);
+        ins.words()
+    }
+
+    fn text(&self, out: &mut String, addr: usize) -> usize {
+        let words = self.texts[&addr];
+        let bytes: Vec<u8> = (addr..addr + words)
+            .flat_map(|a| (self.word(a) as UWord).to_le_bytes())
+            .collect();
+        let len = bytes[0] as usize;
+        let text: Vec<String> = bytes[..=len].iter().map(|b| format!("C{}", b)).collect();
+        let text: Vec<String> = text.chunks(8).map(|c| c.join(" ")).collect();
+        let mut quoted = String::from("\"");
+        for &b in &bytes[1..=len] {
+            match b {
+                b'\n' => quoted.push_str("*N"),
+                b'\t' => quoted.push_str("*T"),
+                b'"' => quoted.push_str("*\""),
+                b'*' => quoted.push_str("**"),
+                32..=126 => quoted.push(b as char),
+                _ => {
+                    let _ = write!(quoted, "*#{:02X}", b);
+                }
+            }
+        }
+        quoted.push('"');
+        let _ = writeln!(out, "    {:<19} / {:5}-{}: {}", text[0], addr, addr + words - 1, quoted);
+        for line in &text[1..] {
+            let _ = writeln!(out, "    {}", line);
+        }
+        words
+    }
+
+    // A D word, shown as DL when it holds the address of labelled code, as
+    // procedure tables and SWITCHON tables do.
+    fn data_word(&self, addr: usize) -> String {
+        let t = self.word(addr) as UWord as usize;
+        if self.labels.contains_key(&t) && self.kind(t) == Kind::Code {
+            format!("D{}", self.label(t))
+        } else {
+            format!("D{}", self.word(addr))
+        }
+    }
+
+    fn switch(&self, out: &mut String, addr: usize) -> usize {
+        let words = self.tables[&addr];
+        self.line(out, &format!("D{}", self.word(addr)), addr, 1, "SWITCHON cases");
+        if words > 1 {
+            self.line(out, &self.data_word(addr + 1), addr + 1, 1, "default");
+        }
+        let mut a = addr + 2;
+        while a + 1 < addr + words {
+            let text = format!("D{} {}", self.word(a), self.data_word(a + 1));
+            self.line(out, &text, a, 2, &format!("case {}", self.word(a)));
+            a += 2;
+        }
+        words
+    }
+}
+
+impl BcplState {
+    /// Decodes the instruction at `addr`, or `None` outside memory.
+    pub fn instruction_at(&self, addr: usize) -> Option<Instruction> {
+        let w = *self.m.get(addr)?;
+        Some(Instruction::decode(w, self.m.get(addr + 1).copied().unwrap_or(0)))
+    }
+
+    /// The loaded code area (`progstart..lomem`) as INTCODE text, with
+    /// addresses and raw words in `/` comments. Labels are renumbered and
+    /// the global initialisations are listed at the end.
+    pub fn disassemble(&self) -> String {
+        let globals = &self.m[..self.progstart];
+        let mut d = Disassembler::new(&self.m, self.progstart, self.lomem);
+        d.analyse(globals);
+        d.listing(globals)
+    }
+}
@@ -0,0 +1,254 @@
+// Errors that stop assembly or a run. The Display text keeps the classic
+// icint messages ("UNKNOWN CALL #87") and adds where the machine was.
+
+use std::fmt;
+
+use crate::{Limit, Word};
+
+/// Where the machine was when an error happened. For assembler errors `pc`
+/// is the load address reached so far and `sp` is 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Context {
+    pub pc: usize,
+    pub sp: usize,
+}
+
+/// The kind of memory access that went outside the store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Instruction fetch.
+    Pc,
+    Load,
+    Store,
+    /// Writing the link words of a procedure frame.
+    Frame,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    BadCh { ch: Word, at: Context },
+    DuplicateLabel { label: Word, at: Context },
+    UnsetLabel { label: Word, at: Context },
+    /// A label number above `MAX_LABEL`.
+    BadLabel { label: usize, at: Context },
+    /// A `G` directive not followed by `L`.
+    BadCode { at: Context },
+    /// A `G` directive for a global that lies in the code area.
+    BadGlobal { global: Word, at: Context },
+    UnknownCall { code: Word, at: Context },
+    /// A call through a global that no `G` directive or store has set,
+    /// and that is not a K-code either. `name` is its header name, if
+    /// symbols were loaded.
+    UndefinedGlobal { global: usize, name: Option<String>, at: Context },
+    UnknownExec { code: Word, at: Context },
+    BadAccess { access: Access, addr: usize, at: Context },
+    /// A coroutine switch with an invalid control block; `what` names the
+    /// field that was wrong.
+    BadChangeco { what: &'static str, value: Word, at: Context },
+    NoInput(String),
+    NoOutput(String),
+    NoIcfile(String),
+    /// A memory geometry that cannot work.
+    BadConfig(String),
+    /// A memory image that cannot be loaded into this machine.
+    BadImage(String),
+    /// A snapshot that cannot be restored into this machine.
+    BadSnapshot(String),
+    /// X6 or X7 with a zero divisor, when arithmetic traps are on.
+    DivideByZero { at: Context },
+    /// X16 or X17 with a negative count or one of `Word::BITS` or more,
+    /// when arithmetic traps are on.
+    BadShift { count: Word, at: Context },
+    /// A result or a number read that does not fit in a word, when the
+    /// overflow check is set to stop.
+    Overflow { expr: String, at: Context },
+    /// A program store into the code area while it is write-protected.
+    WriteProtected { addr: usize, at: Context },
+    /// A replayed run that did something other than what the journal
+    /// recorded, or a journal that cannot be read.
+    Replay { msg: String, at: Context },
+    /// A limit set with `BcplState::set_limits` was reached.
+    LimitExceeded { limit: Limit, at: Context },
+    /// Everything wrong with an INTCODE section, in source order.
+    Assembly(Vec<Diagnostic>),
+}
+
+/// An assembler error and where in the INTCODE source it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub error: VmError,
+    pub file: String,
+    /// Line and column, both from 1.
+    pub line: usize,
+    pub column: usize,
+    /// The text of the offending line.
+    pub excerpt: String,
+}
+
+impl VmError {
+    pub(crate) fn bad_access(access: Access, addr: usize) -> Self {
+        VmError::BadAccess { access, addr, at: Context::default() }
+    }
+
+    /// The machine context of the error, if it has one.
+    pub fn context(&self) -> Option<Context> {
+        match self {
+            VmError::BadCh { at, .. }
+            | VmError::DuplicateLabel { at, .. }
+            | VmError::UnsetLabel { at, .. }
+            | VmError::BadLabel { at, .. }
+            | VmError::BadCode { at }
+            | VmError::BadGlobal { at, .. }
+            | VmError::UnknownCall { at, .. }
+            | VmError::UndefinedGlobal { at, .. }
+            | VmError::UnknownExec { at, .. }
+            | VmError::BadAccess { at, .. }
+            | VmError::BadChangeco { at, .. }
+            | VmError::DivideByZero { at }
+            | VmError::BadShift { at, .. }
+            | VmError::Overflow { at, .. }
+            | VmError::WriteProtected { at, .. }
+            | VmError::Replay { at, .. }
+            | VmError::LimitExceeded { at, .. } => Some(*at),
+            VmError::Assembly(diagnostics) => diagnostics.first().and_then(|d| d.error.context()),
+            VmError::NoInput(_)
+            | VmError::NoOutput(_)
+            | VmError::NoIcfile(_)
+            | VmError::BadConfig(_)
+            | VmError::BadImage(_)
+            | VmError::BadSnapshot(_) => None,
+        }
+    }
+
+    /// Sets the context; used by the interpreter for errors raised below it.
+    pub(crate) fn at(mut self, pc: usize, sp: usize) -> Self {
+        match &mut self {
+            VmError::BadCh { at, .. }
+            | VmError::DuplicateLabel { at, .. }
+            | VmError::UnsetLabel { at, .. }
+            | VmError::BadLabel { at, .. }
+            | VmError::BadCode { at }
+            | VmError::BadGlobal { at, .. }
+            | VmError::UnknownCall { at, .. }
+            | VmError::UndefinedGlobal { at, .. }
+            | VmError::UnknownExec { at, .. }
+            | VmError::BadAccess { at, .. }
+            | VmError::BadChangeco { at, .. }
+            | VmError::DivideByZero { at }
+            | VmError::BadShift { at, .. }
+            | VmError::Overflow { at, .. }
+            | VmError::WriteProtected { at, .. }
+            | VmError::Replay { at, .. }
+            | VmError::LimitExceeded { at, .. } => *at = Context { pc, sp },
+            VmError::Assembly(_)
+            | VmError::NoInput(_)
+            | VmError::NoOutput(_)
+            | VmError::NoIcfile(_)
+            | VmError::BadConfig(_)
+            | VmError::BadImage(_)
+            | VmError::BadSnapshot(_) => {}
+        }
+        self
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::BadCh { ch, at } => write!(f, "BAD CH #{} AT P {}", ch, at.pc),
+            VmError::DuplicateLabel { label, at } => {
+                write!(f, "DUPLICATE LABEL #{} AT P {}", label, at.pc)
+            }
+            VmError::UnsetLabel { label, at } => write!(f, "UNSET LABEL #{} AT P {}", label, at.pc),
+            VmError::BadLabel { label, at } => write!(f, "BAD LABEL #{} AT P {}", label, at.pc),
+            VmError::BadCode { at } => write!(f, "BAD CODE AT P #{}", at.pc),
+            VmError::BadGlobal { global, at } => {
+                write!(f, "GLOBAL #{} IN CODE AREA AT P {}", global, at.pc)
+            }
+            VmError::UnknownCall { code, at } => {
+                write!(f, "UNKNOWN CALL #{} AT PC {} SP {}", code, at.pc, at.sp)
+            }
+            VmError::UndefinedGlobal { global, name, at } => {
+                write!(f, "CALL TO UNDEFINED GLOBAL G{}", global)?;
+                if let Some(name) = name {
+                    write!(f, " ({})", name)?;
+                }
+                write!(f, " AT PC {} SP {}", at.pc, at.sp)
+            }
+            VmError::UnknownExec { code, at } => {
+                write!(f, "UNKNOWN EXEC #{} AT PC {} SP {}", code, at.pc, at.sp)
+            }
+            VmError::BadAccess { access, addr, at } => {
+                let what = match access {
+                    Access::Pc => "BAD PC",
+                    Access::Load => "BAD LOAD",
+                    Access::Store => "BAD STORE",
+                    Access::Frame => "BAD FRAME",
+                };
+                write!(f, "{} #{} AT PC {} SP {}", what, addr, at.pc, at.sp)
+            }
+            VmError::BadChangeco { what, value, at } => {
+                write!(f, "BAD CHANGECO {} #{} AT PC {} SP {}", what, value, at.pc, at.sp)
+            }
+            VmError::DivideByZero { at } => {
+                write!(f, "DIVISION BY ZERO AT PC {} SP {}", at.pc, at.sp)
+            }
+            VmError::BadShift { count, at } => {
+                write!(f, "BAD SHIFT #{} AT PC {} SP {}", count, at.pc, at.sp)
+            }
+            VmError::Overflow { expr, at } => {
+                write!(f, "ARITHMETIC OVERFLOW {} AT PC {} SP {}", expr, at.pc, at.sp)
+            }
+            VmError::WriteProtected { addr, at } => {
+                write!(f, "STORE TO CODE #{} AT PC {} SP {}", addr, at.pc, at.sp)
+            }
+            VmError::Replay { msg, at } => {
+                write!(f, "REPLAY FAILED: {} AT PC {} SP {}", msg, at.pc, at.sp)
+            }
+            VmError::LimitExceeded { limit, at } => {
+                match limit {
+                    Limit::Instructions(n) => write!(f, "INSTRUCTION LIMIT {} REACHED", n)?,
+                    Limit::Time(t) => write!(f, "TIME LIMIT {}S REACHED", t.as_secs_f64())?,
+                    Limit::Output(n) => write!(f, "OUTPUT LIMIT {} BYTES REACHED", n)?,
+                    Limit::Streams(n) => write!(f, "STREAM LIMIT {} REACHED", n)?,
+                }
+                write!(f, " AT PC {} SP {}", at.pc, at.sp)
+            }
+            VmError::NoInput(name) => write!(f, "NO INPUT {}", name),
+            VmError::NoOutput(name) => write!(f, "NO OUTPUT {}", name),
+            VmError::NoIcfile(name) => write!(f, "NO ICFILE {}", name),
+            VmError::BadConfig(msg) => write!(f, "BAD CONFIG: {}", msg),
+            VmError::BadImage(msg) => write!(f, "BAD IMAGE {}", msg),
+            VmError::BadSnapshot(msg) => write!(f, "BAD SNAPSHOT {}", msg),
+            VmError::Assembly(diagnostics) => {
+                for (i, d) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", d)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// FILE:LINE:COLUMN: MESSAGE, then the line with a caret under the column.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.error)?;
+        if !self.excerpt.is_empty() {
+            // Keep tabs so the caret lines up however they are displayed.
+            let pad: String = self
+                .excerpt
+                .chars()
+                .take(self.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            write!(f, "\n    {}\n    {}^", self.excerpt, pad)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VmError {}
@@ -0,0 +1,107 @@
+// Flight recorder. A fault such as BAD CHANGECO is usually caused a few
+// hundred instructions before it is detected, and a full trace of a long
+// run is far too big to keep. The recorder keeps only the last N
+// instructions in a ring, which costs a few stores per instruction while it
+// is on, a fifth or more of the time on tight loops. It is off unless asked
+// for, and then `run` uses the same loop as before.
+
+use crate::*;
+
+/// Most instructions the flight recorder will keep.
+pub const MAX_FLIGHT_RECORD: usize = 1 << 20;
+
+/// An instruction the flight recorder saw, with the registers it started
+/// with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlightEntry {
+    pub pc: UWord,
+    pub sp: UWord,
+    pub a: Word,
+    pub b: Word,
+    // The instruction word and its operand before P or I were applied,
+    // decoded when asked for
+    w: UWord,
+    d: UWord,
+}
+
+impl FlightEntry {
+    /// The instruction as it was when executed.
+    pub fn instruction(&self) -> Instruction {
+        Instruction::decode(self.w as Word, self.d as Word)
+    }
+}
+
+// The ring is a power of two long, so an entry's place is the count of
+// instructions recorded masked by the length less one.
+pub(crate) struct FlightRecorder {
+    entries: Vec<FlightEntry>,
+    // Instructions recorded since the ring was cleared
+    count: usize,
+    // Entries kept when dumped, which may be fewer than the ring holds
+    keep: usize,
+}
+
+impl FlightRecorder {
+    // Keeps the last `n` instructions, at most `MAX_FLIGHT_RECORD`.
+    pub(crate) fn new(n: usize) -> Self {
+        let n = n.min(MAX_FLIGHT_RECORD);
+        let entries = vec![FlightEntry::default(); if n == 0 { 0 } else { n.next_power_of_two() }];
+        FlightRecorder { entries, count: 0, keep: n }
+    }
+
+    pub(crate) fn is_on(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.count = 0;
+    }
+}
+
+impl BcplState {
+    /// Keeps the last `n` instructions executed, for `flight_record`. Zero,
+    /// the default, turns the recorder off; more than `MAX_FLIGHT_RECORD`
+    /// keeps that many.
+    pub fn set_flight_recorder(&mut self, n: usize) {
+        self.flight = FlightRecorder::new(n);
+    }
+
+    // Notes the instruction word `w` with operand `d` fetched from `pc`,
+    // about to run with the A, B and SP in `r`.
+    #[inline(always)]
+    pub(crate) fn record_flight(&mut self, pc: UWord, w: UWord, d: UWord, r: &Regs) {
+        let f = &mut self.flight;
+        let i = f.count & (f.entries.len() - 1);
+        f.entries[i] = FlightEntry { pc, sp: r.sp, a: r.a, b: r.b, w, d };
+        f.count = f.count.wrapping_add(1);
+    }
+
+    pub(crate) fn clear_flight(&mut self) {
+        self.flight.clear();
+    }
+
+    /// The instructions the recorder holds, oldest first. After a fault
+    /// the last is the one that failed.
+    pub fn flight_record(&self) -> Vec<FlightEntry> {
+        let f = &self.flight;
+        let len = f.count.min(f.keep);
+        (f.count - len..f.count).map(|i| f.entries[i & (f.entries.len() - 1)]).collect()
+    }
+
+    /// The flight record as a table, one instruction to a line.
+    pub fn format_flight_record(&self, entries: &[FlightEntry]) -> String {
+        let mut out = format!("LAST {} INSTRUCTIONS\n", entries.len());
+        out.push_str("     PC  INSTRUCTION       A       B     SP");
+        for e in entries {
+            out.push_str(&format!(
+                "\n  {:5}  {:<10}  {:6}  {:6}  {:5}",
+                e.pc,
+                e.instruction().to_string(),
+                e.a,
+                e.b,
+                e.sp
+            ));
+        }
+        out
+    }
+}
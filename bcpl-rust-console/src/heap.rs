@@ -0,0 +1,89 @@
+// GETVEC/FREEVEC allocator for the coroutine runtime. Blocks are carved
+// downwards from the top of memory towards the stack; freed blocks go on a
+// free list that is kept sorted and coalesced. The size of each block handed
+// out is kept by its address, so only live blocks cost anything.
+
+use std::collections::BTreeMap;
+
+use crate::{UWord, Word};
+
+pub(crate) struct Heap {
+    pub(crate) wordcount: usize,
+    pub(crate) top: usize,
+    pub(crate) free_list: Vec<(usize, usize)>,
+    pub(crate) alloc_sizes: BTreeMap<usize, usize>,
+}
+
+impl Heap {
+    pub(crate) fn new(wordcount: usize) -> Self {
+        Heap {
+            wordcount,
+            top: wordcount - 1,
+            free_list: Vec::new(),
+            alloc_sizes: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.top = self.wordcount - 1;
+        self.free_list.clear();
+        self.alloc_sizes.clear();
+    }
+
+    pub(crate) fn getvec(&mut self, words: usize, sp: UWord) -> Word {
+        if words == 0 || words >= self.wordcount {
+            return 0;
+        }
+
+        if let Some((idx, (addr, size))) = self
+            .free_list
+            .iter()
+            .enumerate()
+            .find(|(_, (_, size))| *size >= words)
+        {
+            let (addr, size) = (*addr, *size);
+            if size == words {
+                self.free_list.swap_remove(idx);
+            } else {
+                self.free_list[idx] = (addr + words, size - words);
+            }
+            self.alloc_sizes.insert(addr, words);
+            return addr as Word;
+        }
+
+        if self.top < words {
+            return 0;
+        }
+
+        let start = self.top + 1 - words;
+        if start <= sp as usize + 1 {
+            return 0;
+        }
+
+        self.top = start - 1;
+        self.alloc_sizes.insert(start, words);
+        start as Word
+    }
+
+    pub(crate) fn freevec(&mut self, addr: usize) -> Word {
+        let Some(size) = self.alloc_sizes.remove(&addr) else {
+            return 0;
+        };
+
+        self.free_list.push((addr, size));
+        self.free_list.sort_by_key(|(a, _)| *a);
+
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.free_list.len());
+        for (a, s) in self.free_list.drain(..) {
+            if let Some((last_a, last_s)) = merged.last_mut()
+                && *last_a + *last_s == a
+            {
+                *last_s += s;
+                continue;
+            }
+            merged.push((a, s));
+        }
+        self.free_list = merged;
+        1
+    }
+}
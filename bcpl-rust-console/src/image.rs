@@ -0,0 +1,165 @@
+// Memory images. An image holds the code area and global initialisations
+// left by assembling INTCODE, so a large program such as the compiler can be
+// loaded without reading its text again. All fields are little-endian:
+//
+//   magic "ICINTIMG", version (2 bytes), bytes per word (2 bytes),
+//   progstart, lomem and the number of global initialisations (4 bytes each),
+//   then each initialisation as a global number (4 bytes) and a word,
+//   then the words from progstart up to lomem,
+//   then a bit per word from progstart, set for instructions, eight to a
+//   byte starting from the low bit.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::*;
+
+const MAGIC: &[u8; 8] = b"ICINTIMG";
+/// Version of the image format written by this build.
+pub const IMAGE_VERSION: u16 = 2;
+
+fn bad_image(msg: &str) -> VmError {
+    VmError::BadImage(msg.to_string())
+}
+
+fn truncated(_: io::Error) -> VmError {
+    bad_image("TRUNCATED")
+}
+
+// Readers shared with snapshots, which report a short file their own way.
+pub(crate) fn read_u32(input: &mut impl Read) -> io::Result<usize> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf) as usize)
+}
+
+pub(crate) fn read_u16(input: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    input.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+pub(crate) fn read_word(input: &mut impl Read) -> io::Result<Word> {
+    let mut buf = [0u8; BYTESPERWORD];
+    input.read_exact(&mut buf)?;
+    Ok(Word::from_le_bytes(buf))
+}
+
+// A bit per flag, eight to a byte starting from the low bit.
+pub(crate) fn write_bits(out: &mut impl Write, flags: impl Iterator<Item = bool>) -> io::Result<()> {
+    let mut bits = Vec::new();
+    for (i, flag) in flags.enumerate() {
+        if i % 8 == 0 {
+            bits.push(0u8);
+        }
+        if flag {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+    out.write_all(&bits)
+}
+
+pub(crate) fn read_bits(input: &mut impl Read, n: usize) -> io::Result<Vec<bool>> {
+    let mut bits = vec![0u8; n.div_ceil(8)];
+    input.read_exact(&mut bits)?;
+    Ok((0..n).map(|i| bits[i / 8] & 1 << (i % 8) != 0).collect())
+}
+
+impl BcplState {
+    /// Writes the loaded program as an image.
+    pub fn write_image(&self, mut out: impl Write) -> io::Result<()> {
+        let inits: Vec<usize> = (0..self.progstart)
+            .filter(|&g| self.m[g] != g as Word)
+            .collect();
+
+        out.write_all(MAGIC)?;
+        out.write_all(&IMAGE_VERSION.to_le_bytes())?;
+        out.write_all(&(BYTESPERWORD as u16).to_le_bytes())?;
+        for n in [self.progstart, self.lomem, inits.len()] {
+            out.write_all(&(n as u32).to_le_bytes())?;
+        }
+        for g in inits {
+            out.write_all(&(g as u32).to_le_bytes())?;
+            out.write_all(&self.m[g].to_le_bytes())?;
+        }
+        for w in &self.m[self.progstart..self.lomem] {
+            out.write_all(&w.to_le_bytes())?;
+        }
+        let code = (self.progstart..self.lomem).map(|a| self.code_words.get(a) == Some(&true));
+        write_bits(&mut out, code)?;
+        out.flush()
+    }
+
+    /// Loads an image written by `write_image`, replacing the code area.
+    /// The machine must have the same PROGSTART and room for the code.
+    pub fn read_image(&mut self, mut input: impl Read) -> Result<(), VmError> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != MAGIC {
+            return Err(bad_image("NOT AN ICINT IMAGE"));
+        }
+        let version = read_u16(&mut input).map_err(truncated)?;
+        if version != IMAGE_VERSION {
+            return Err(VmError::BadImage(format!("VERSION {} NOT SUPPORTED", version)));
+        }
+        let word_bytes = read_u16(&mut input).map_err(truncated)? as usize;
+        if word_bytes != BYTESPERWORD {
+            return Err(VmError::BadImage(format!("{}-BYTE WORDS", word_bytes)));
+        }
+        let progstart = read_u32(&mut input).map_err(truncated)?;
+        if progstart != self.progstart {
+            return Err(VmError::BadImage(format!(
+                "PROGSTART {} BUT MACHINE HAS {}",
+                progstart, self.progstart
+            )));
+        }
+        let lomem = read_u32(&mut input).map_err(truncated)?;
+        if lomem < progstart || lomem >= self.m.len() {
+            return Err(VmError::BadImage(format!("LOMEM {} DOES NOT FIT", lomem)));
+        }
+
+        // Read everything before touching memory, so a bad image leaves the
+        // machine as it was.
+        let mut inits = Vec::new();
+        for _ in 0..read_u32(&mut input).map_err(truncated)? {
+            let g = read_u32(&mut input).map_err(truncated)?;
+            if g >= progstart {
+                return Err(VmError::BadImage(format!("GLOBAL {} IN CODE AREA", g)));
+            }
+            inits.push((g, read_word(&mut input).map_err(truncated)?));
+        }
+        let code = (progstart..lomem)
+            .map(|_| read_word(&mut input))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(truncated)?;
+        let bits = read_bits(&mut input, lomem - progstart).map_err(truncated)?;
+
+        for (g, value) in inits {
+            self.m[g] = value;
+            self.entry_globals[g] = true;
+            self.assigned[g] = true;
+        }
+        self.m[progstart..lomem].copy_from_slice(&code);
+        self.code_words = vec![false; progstart];
+        self.code_words.extend(bits);
+        self.lomem = lomem;
+        self.guard();
+        Ok(())
+    }
+
+    /// Writes the loaded program to the image file `filename`.
+    pub fn save_image(&self, filename: &str) -> Result<(), VmError> {
+        let file = File::create(filename).map_err(|_| VmError::NoOutput(filename.to_string()))?;
+        self.write_image(BufWriter::new(file))
+            .map_err(|_| VmError::NoOutput(filename.to_string()))
+    }
+
+    /// Loads the image file `filename` in place of assembling INTCODE.
+    pub fn load_image(&mut self, filename: &str) -> Result<(), VmError> {
+        let file = File::open(filename).map_err(|_| VmError::NoIcfile(filename.to_string()))?;
+        self.read_image(BufReader::new(file)).map_err(|e| match e {
+            VmError::BadImage(msg) => VmError::BadImage(format!("{} {}", filename, msg)),
+            e => e,
+        })
+    }
+}
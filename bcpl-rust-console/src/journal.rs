@@ -0,0 +1,309 @@
+// Record and replay. Everything a program learns from outside the machine
+// arrives as the result of RDCH (READN reads through it) or of a K-code
+// such as FINDINPUT, so logging those results is enough to run the program
+// again exactly. The log is text, one event to a line:
+//
+//   ICINT JOURNAL 2
+//   R 72 SYSIN    RDCH from SYSIN returned 72 ('H'); -1 is ENDSTREAMCH
+//   K 42 4        K-code 42 (FINDINPUT) returned stream 4
+//   R 49 DATA     RDCH from the stream FINDINPUT opened as "DATA"
+//
+// Streams are named by what they are to the program rather than by their
+// place in the stream table, which depends on how the run was started: a
+// log recorded with -i replays without it. A stream that is neither has
+// its table index, as "#5".
+//
+// In replay RDCH takes its characters from the log without reading the
+// stream, and FINDINPUT gives a new empty stream without opening a file, so
+// the input files need not exist. FINDOUTPUT still opens its file, and the
+// run stops if one opens where the other failed. Host K-codes marked with
+// `set_nondeterministic` are not called in replay; their logged result is
+// returned instead.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::*;
+
+const HEADER: &str = "ICINT JOURNAL 2";
+
+enum Mode {
+    Record(Box<dyn Stream>),
+    Replay { log: Box<dyn Stream>, line: usize },
+}
+
+/// Where a run's input is logged to, or replayed from.
+pub struct Journal {
+    mode: Mode,
+    // The first replay failure, reported when the K-code that met it returns
+    fault: Option<String>,
+    // Names given to FINDINPUT, by the stream it returned
+    inputs: HashMap<usize, String>,
+}
+
+// One line of the log.
+#[derive(Clone, PartialEq, Eq)]
+enum Event {
+    Rdch { stream: String, ch: Word },
+    KCode { code: Word, result: Word },
+}
+
+// What happened, without its result.
+fn kind(event: &Event) -> String {
+    match event {
+        Event::Rdch { stream, .. } => format!("RDCH FROM {}", stream),
+        Event::KCode { code, .. } => format!("K-CODE {}", code),
+    }
+}
+
+fn with_result(event: &Event) -> String {
+    match event {
+        Event::Rdch { ch, .. } => format!("{} RETURNING {}", kind(event), ch),
+        Event::KCode { result, .. } => format!("{} RETURNING {}", kind(event), result),
+    }
+}
+
+fn parse(text: &str) -> Option<Event> {
+    let mut fields = text.splitn(3, ' ');
+    let kind = fields.next()?;
+    let n = fields.next()?.parse().ok()?;
+    let rest = fields.next().filter(|f| !f.is_empty())?;
+    match kind {
+        "R" => Some(Event::Rdch { stream: rest.to_string(), ch: n }),
+        "K" => Some(Event::KCode { code: n, result: rest.parse().ok()? }),
+        _ => None,
+    }
+}
+
+// Whether K-code `code` returns a stream, whose number may differ between
+// runs.
+fn opens_stream(code: Word) -> bool {
+    code == K41_FINDOUTPUT || code == K42_FINDINPUT
+}
+
+impl Journal {
+    /// Logs the run's input to `out`.
+    pub fn record(mut out: Box<dyn Stream>) -> Self {
+        out.write_bytes(HEADER.as_bytes());
+        out.write_bytes(b"\n");
+        Journal { mode: Mode::Record(out), fault: None, inputs: HashMap::new() }
+    }
+
+    /// Feeds a run the input logged in `log` by `record`.
+    pub fn replay(log: Box<dyn Stream>) -> Self {
+        Journal { mode: Mode::Replay { log, line: 0 }, fault: None, inputs: HashMap::new() }
+    }
+
+    fn replaying(&self) -> bool {
+        matches!(self.mode, Mode::Replay { .. })
+    }
+
+    fn log(&mut self, event: &Event) {
+        let Mode::Record(out) = &mut self.mode else {
+            return;
+        };
+        let line = match event {
+            Event::Rdch { stream, ch } => format!("R {} {}", ch, stream),
+            Event::KCode { code, result } => format!("K {} {}", code, result),
+        };
+        out.write_bytes(line.as_bytes());
+        out.write_bytes(b"\n");
+        // Keep the log whole up to the last line read, in case the run is
+        // interrupted.
+        if let Event::Rdch { ch, .. } = *event
+            && (ch == ASC_LF as Word || ch == ENDSTREAMCH)
+        {
+            out.flush();
+        }
+    }
+
+    // The next event in the log, or None at its end. A malformed line is a
+    // fault.
+    fn next(&mut self) -> Option<Event> {
+        let Mode::Replay { log, line } = &mut self.mode else {
+            return None;
+        };
+        loop {
+            let mut text = Vec::new();
+            let mut ended = true;
+            while let Some(c) = log.read_byte() {
+                ended = false;
+                if c == b'\n' {
+                    break;
+                }
+                text.push(c);
+            }
+            if ended {
+                return None;
+            }
+            *line += 1;
+            let line = *line;
+            let text = String::from_utf8_lossy(&text);
+            let text = text.trim_end_matches('\r');
+            if text.is_empty() || line == 1 && text == HEADER {
+                continue;
+            }
+            if line == 1 && text.starts_with("ICINT JOURNAL") {
+                self.fail(format!("LOG IS {}, NOT {}", text, HEADER));
+                return None;
+            }
+            let event = parse(text);
+            if event.is_none() {
+                self.fail(format!("BAD LOG LINE {}", line));
+            }
+            return event;
+        }
+    }
+
+    // Takes the next event, which should match `wanted` in kind; a
+    // different one is a fault.
+    fn expect(&mut self, wanted: &Event) -> Option<Event> {
+        let event = self.next();
+        let same_kind = match (&event, wanted) {
+            (Some(Event::Rdch { stream, .. }), Event::Rdch { stream: s, .. }) => stream == s,
+            (Some(Event::KCode { code, .. }), Event::KCode { code: c, .. }) => code == c,
+            _ => false,
+        };
+        if same_kind {
+            return event;
+        }
+        let msg = match &event {
+            Some(event) => format!("LOG HAS {} WHERE PROGRAM DID {}", with_result(event), kind(wanted)),
+            None => format!("LOG ENDED BEFORE {}", kind(wanted)),
+        };
+        self.fail(msg);
+        None
+    }
+
+    fn fail(&mut self, msg: String) {
+        self.fault.get_or_insert(msg);
+    }
+}
+
+impl BcplState {
+    /// Sets the journal that records or replays the program's input. Set
+    /// it after loading, so the INTCODE read by the assembler is left out.
+    pub fn set_journal(&mut self, journal: Option<Journal>) {
+        self.journal = journal;
+    }
+
+    /// Marks host K-code `n` as depending on the world outside the machine,
+    /// such as the time of day. Its result in `A` is logged when recording,
+    /// and replayed without calling it. Anything else it does is not.
+    pub fn set_nondeterministic(&mut self, n: usize, nondeterministic: bool) {
+        if self.nondeterministic.len() <= n {
+            self.nondeterministic.resize(n + 1, false);
+        }
+        self.nondeterministic[n] = nondeterministic;
+    }
+
+    // How the log names stream `f`.
+    fn stream_role(&self, f: usize) -> String {
+        let named = self.journal.as_ref().and_then(|j| j.inputs.get(&f));
+        match named {
+            _ if f == self.sysin => "SYSIN".to_string(),
+            Some(name) => name.clone(),
+            None => format!("#{}", f),
+        }
+    }
+
+    // RDCH with a journal set.
+    pub(crate) fn journal_rdch(&mut self) -> Word {
+        let stream = self.stream_role(self.cis);
+        let replaying = self.journal.as_ref().is_some_and(Journal::replaying);
+        let ch = if replaying { ENDSTREAMCH } else { self.read_ch() };
+        let Some(journal) = &mut self.journal else {
+            return ch;
+        };
+        let event = Event::Rdch { stream, ch };
+        if !replaying {
+            journal.log(&event);
+            return ch;
+        }
+        match journal.expect(&event) {
+            Some(Event::Rdch { ch, .. }) => ch,
+            _ => ENDSTREAMCH,
+        }
+    }
+
+    fn marked(&self, code: Word) -> bool {
+        self.nondeterministic.get(code as UWord as usize) == Some(&true)
+    }
+
+    // Whether the result of K-code `code` goes in the log.
+    pub(crate) fn journaled(&self, code: Word) -> bool {
+        self.journal.is_some() && (opens_stream(code) || self.marked(code))
+    }
+
+    // Whether K-code `code` has its result taken from the log rather than
+    // computed.
+    pub(crate) fn replays(&self, code: Word) -> bool {
+        let replaying = self.journal.as_ref().is_some_and(Journal::replaying);
+        replaying && (code == K42_FINDINPUT || self.marked(code))
+    }
+
+    // The logged result of the K-code `call` makes, which `replays` allows.
+    pub(crate) fn replayed_result(&mut self, call: &KCall) -> Result<Word, VmError> {
+        let code = call.a;
+        let journal = self.journal.as_mut().expect("replaying");
+        let result = match journal.expect(&Event::KCode { code, result: 0 }) {
+            Some(Event::KCode { result, .. }) => result,
+            _ => 0,
+        };
+        self.check_journal()?;
+        if code != K42_FINDINPUT || result == 0 {
+            return Ok(result);
+        }
+        // A stream with nothing in it stands for the one the recorded run
+        // opened: its characters come from the log.
+        let name = self.cstr(call.arg_addr(self, 0)?)?;
+        let f = self.add_stream(Box::new(stream::ReadStream(Cursor::new(Vec::new()))));
+        self.journal.as_mut().expect("replaying").inputs.insert(f, name);
+        Ok(f as Word)
+    }
+
+    // Logs the result in `A` of the K-code `call` made, or when replaying
+    // checks it against the one logged.
+    pub(crate) fn journal_result(&mut self, code: Word, call: &KCall) -> Result<(), VmError> {
+        let result = call.a;
+        if code == K42_FINDINPUT && result != 0 {
+            let name = self.cstr(call.arg_addr(self, 0)?)?;
+            if let Some(journal) = &mut self.journal {
+                journal.inputs.insert(result as UWord as usize, name);
+            }
+        }
+        let Some(journal) = &mut self.journal else {
+            return Ok(());
+        };
+        let event = Event::KCode { code, result };
+        if !journal.replaying() {
+            journal.log(&event);
+            return Ok(());
+        }
+        // Stream numbers depend on how the run was started; only whether
+        // one was opened must match.
+        let agrees = |logged: Word| {
+            if opens_stream(code) { (logged != 0) == (result != 0) } else { logged == result }
+        };
+        if let Some(logged @ Event::KCode { result: r, .. }) = journal.expect(&event)
+            && !agrees(r)
+        {
+            journal.fail(format!("LOG HAS {} WHERE PROGRAM GOT {}", with_result(&logged), result));
+        }
+        self.check_journal()
+    }
+
+    // Fails once the replay has gone wrong.
+    pub(crate) fn check_journal(&mut self) -> Result<(), VmError> {
+        match self.journal.as_mut().and_then(|j| j.fault.take()) {
+            Some(msg) => Err(VmError::Replay { msg, at: Context::default() }),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn flush_journal(&mut self) {
+        if let Some(Journal { mode: Mode::Record(out), .. }) = &mut self.journal {
+            out.flush();
+        }
+    }
+}
@@ -0,0 +1,270 @@
+// K-code (system call) registry. A `K` instruction whose target is below
+// the code area is dispatched here by number; the built-in library routines are
+// registered through the same table as host-defined ones.
+
+use crate::*;
+
+/// Machine registers seen by a K-code handler.
+///
+/// The handler may change `a` to return a result, and `pc`/`sp` to transfer
+/// control (as `LONGJUMP` and `APTOVEC` do).
+pub struct KCall {
+    pub pc: UWord,
+    pub sp: UWord,
+    pub a: Word,
+    /// Frame base of the call, i.e. `sp + d` of the `K` instruction.
+    pub d_addr: UWord,
+}
+
+impl KCall {
+    /// Address of the first argument; further arguments follow it.
+    pub fn args(&self) -> usize {
+        self.d_addr.wrapping_add(2) as usize
+    }
+
+    /// Argument `i` (from 0) of the call.
+    pub fn arg(&self, state: &BcplState, i: usize) -> Result<Word, VmError> {
+        state.load(self.args() + i)
+    }
+
+    /// Argument `i` taken as a word address.
+    pub fn arg_addr(&self, state: &BcplState, i: usize) -> Result<usize, VmError> {
+        Ok(self.arg(state, i)? as UWord as usize)
+    }
+}
+
+/// What the interpreter does after a K-code handler returns.
+pub enum KResult {
+    Continue,
+    /// End the run with this stop code.
+    Stop(Word),
+}
+
+pub type KHandler = Box<dyn FnMut(&mut BcplState, &mut KCall) -> Result<KResult, VmError>>;
+
+pub(crate) fn register_builtins(state: &mut BcplState) {
+    state.register_kcode(K01_START, |_, _| Ok(KResult::Continue));
+    // Writes the caller's backtrace to the current output, through wrch so
+    // that it counts against the output limit like any other output.
+    state.register_kcode(K04_BACKTRACE, |s, k| {
+        let text = s.format_backtrace(&s.backtrace_from(k.pc, k.sp));
+        for c in text.bytes() {
+            s.wrch(c as Word);
+        }
+        s.newline();
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K11_SELECTINPUT, |s, k| {
+        s.cis = k.arg(s, 0)? as usize;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K12_SELECTOUTPUT, |s, k| {
+        s.cos = k.arg(s, 0)? as usize;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K13_RDCH, |s, k| {
+        k.a = s.rdch();
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K14_WRCH, |s, k| {
+        s.wrch(k.arg(s, 0)?);
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K16_INPUT, |s, k| {
+        k.a = s.cis as Word;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K17_OUTPUT, |s, k| {
+        k.a = s.cos as Word;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K30_STOP, |s, k| Ok(KResult::Stop(k.arg(s, 0)?)));
+    state.register_kcode(K31_LEVEL, |_, k| {
+        k.a = k.sp as Word;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K32_LONGJUMP, |s, k| {
+        k.sp = k.arg(s, 0)? as UWord;
+        k.pc = k.arg(s, 1)? as UWord;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K40_APTOVEC, |s, k| {
+        let b_addr = k.d_addr.wrapping_add(k.arg(s, 1)? as UWord).wrapping_add(1);
+        #[cfg(feature = "coroutines")]
+        if s.co_debug {
+            eprintln!(
+                "APTOVEC: sp={} d_addr={} argc={} b_addr={} pc={}",
+                k.sp,
+                k.d_addr,
+                k.arg(s, 1)?,
+                b_addr,
+                k.pc
+            );
+        }
+        s.store(b_addr as usize, k.sp as Word)?;
+        s.store(b_addr as usize + 1, k.pc as Word)?;
+        s.store(b_addr as usize + 2, k.d_addr as Word)?;
+        s.store(b_addr as usize + 3, k.arg(s, 1)?)?;
+        k.sp = b_addr;
+        k.pc = k.arg(s, 0)? as UWord;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K41_FINDOUTPUT, |s, k| {
+        k.a = s.findoutput(k.arg_addr(s, 0)?)? as Word;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K42_FINDINPUT, |s, k| {
+        k.a = s.findinput(k.arg_addr(s, 0)?)? as Word;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K46_ENDREAD, |s, _| {
+        s.endread();
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K47_ENDWRITE, |s, _| {
+        s.endwrite();
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K60_WRITES, |s, k| {
+        s.writes(k.arg_addr(s, 0)?)?;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K62_WRITEN, |s, k| {
+        s.writen(k.arg(s, 0)?);
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K63_NEWLINE, |s, _| {
+        s.newline();
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K64_NEWPAGE, |s, _| {
+        s.wrch(ASC_FF as Word);
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K66_PACKSTRING, |s, k| {
+        k.a = s.packstring(k.arg_addr(s, 0)?, k.arg_addr(s, 1)?)?;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K67_UNPACKSTRING, |s, k| {
+        s.unpackstring(k.arg_addr(s, 0)?, k.arg_addr(s, 1)?)?;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K68_WRITED, |s, k| {
+        s.writed(k.arg(s, 0)?, k.arg(s, 1)?);
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K70_READN, |s, k| {
+        k.a = s.readn(s.kcode_at)?;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K75_WRITEHEX, |s, k| {
+        s.writehex(k.arg(s, 0)? as UWord, k.arg(s, 1)?);
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K76_WRITEF, |s, k| {
+        s.writef(k.args())?;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K77_WRITEOCT, |s, k| {
+        s.writeoct(k.arg(s, 0)? as UWord, k.arg(s, 1)?);
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K85_GETBYTE, |s, k| {
+        k.a = s.get_byte(byte_index(s, k, Access::Load)?)? as Word;
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K86_PUTBYTE, |s, k| {
+        s.set_byte(byte_index(s, k, Access::Store)?, k.arg(s, 2)? as u8)?;
+        Ok(KResult::Continue)
+    });
+
+    #[cfg(feature = "coroutines")]
+    register_coroutines(state);
+}
+
+// Byte index of GETBYTE/PUTBYTE(V, N). An offset that takes it past the
+// largest index (a negative one, say) is a bad access to V.
+fn byte_index(s: &BcplState, k: &KCall, access: Access) -> Result<usize, VmError> {
+    let vector = k.arg_addr(s, 0)?;
+    let offset = k.arg(s, 1)? as usize;
+    (vector * BYTESPERWORD)
+        .checked_add(offset)
+        .ok_or_else(|| VmError::bad_access(access, vector))
+}
+
+#[cfg(feature = "coroutines")]
+fn register_coroutines(state: &mut BcplState) {
+    state.register_kcode(K87_GETVEC, |s, k| {
+        let words = k.arg_addr(s, 0)?;
+        k.a = s.heap.getvec(words, k.sp);
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K88_FREEVEC, |s, k| {
+        let addr = k.arg_addr(s, 0)?;
+        k.a = s.heap.freevec(addr);
+        Ok(KResult::Continue)
+    });
+    state.register_kcode(K90_CHANGECO, changeco);
+}
+
+// Changeco(A, Cptr, CurrcoAddr) with saved sp/pc
+#[cfg(feature = "coroutines")]
+fn changeco(s: &mut BcplState, k: &mut KCall) -> Result<KResult, VmError> {
+    let arg = k.arg(s, 0)?;
+    let cptr = k.arg_addr(s, 1)?;
+    let currco_addr = k.arg_addr(s, 2)?;
+
+    if cptr == 0 || cptr + 1 >= s.m.len() {
+        return Err(changeco_error("C", cptr as Word));
+    }
+    if currco_addr >= s.m.len() {
+        return Err(changeco_error("CURRCO", currco_addr as Word));
+    }
+
+    let currco = s.m[currco_addr] as UWord as usize;
+    if s.co_debug {
+        eprintln!(
+            "CHANGECO enter: arg={} currco_addr={} currco={} -> cptr={} sp={} pc={}",
+            arg, currco_addr, currco, cptr, k.sp, k.pc
+        );
+        if cptr < s.m.len().saturating_sub(6) {
+            eprintln!(
+                "CHANGECO cptr fields: sp={} pc={} parent={} next={} f={} size={} self={}",
+                s.m[cptr],
+                s.m[cptr + 1],
+                s.m[cptr + 2],
+                s.m[cptr + 3],
+                s.m[cptr + 4],
+                s.m[cptr + 5],
+                s.m[cptr + 6]
+            );
+        }
+    }
+    if currco != 0 {
+        s.store(currco, k.sp as Word)?;
+        s.store(currco + 1, k.pc as Word)?;
+    }
+
+    s.store(currco_addr, cptr as Word)?;
+    k.sp = s.m[cptr] as UWord;
+    k.pc = s.m[cptr + 1] as UWord;
+    if k.sp as usize >= s.m.len() || (k.sp as usize) < s.progstart {
+        return Err(changeco_error("SP", k.sp as Word));
+    }
+    if k.pc as usize >= s.m.len() || (k.pc as usize) < s.progstart {
+        return Err(changeco_error("PC", k.pc as Word));
+    }
+    k.a = arg;
+    if s.co_debug {
+        eprintln!(
+            "CHANGECO exit: currco_addr={} currco={} sp={} pc={}",
+            currco_addr, cptr, k.sp, k.pc
+        );
+    }
+    Ok(KResult::Continue)
+}
+
+#[cfg(feature = "coroutines")]
+fn changeco_error(what: &'static str, value: Word) -> VmError {
+    VmError::BadChangeco { what, value, at: Context::default() }
+}
@@ -0,0 +1,1313 @@
+//! BCPL INTCODE assembler and interpreter.
+//!
+//! `BcplState` holds the whole virtual machine: the word memory, the global
+//! vector and the stream table. A host loads one or more INTCODE sections
+//! (from a file or a string), runs them with `interpret` and can then inspect
+//! memory and globals.
+//!
+//! ```no_run
+//! let mut state = icint::BcplState::new();
+//! state.loadcode("INTCODE")?;
+//! let code = state.interpret()?;
+//! println!("stopped with {} (G2 = {})", code, state.global(2));
+//! # Ok::<(), icint::VmError>(())
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::Cursor;
+use std::ops::Range;
+
+#[cfg(feature = "coroutines")]
+mod heap;
+mod assembler;
+mod backtrace;
+mod config;
+mod debugger;
+mod disasm;
+mod error;
+mod flight;
+mod image;
+mod journal;
+mod kcode;
+mod limits;
+mod profile;
+mod protect;
+mod snapshot;
+pub mod stream;
+mod symbols;
+mod trace;
+
+pub use assembler::MAX_LABEL;
+pub use backtrace::Frame;
+pub use config::{Config, MAX_WORDCOUNT};
+pub use debugger::Debugger;
+pub use disasm::{Instruction, xcode_name};
+pub use error::{Access, Context, Diagnostic, VmError};
+pub use flight::{FlightEntry, MAX_FLIGHT_RECORD};
+pub use image::IMAGE_VERSION;
+pub use journal::Journal;
+pub use kcode::{KCall, KHandler, KResult};
+pub use limits::{Limit, Limits};
+pub use profile::Profile;
+pub use protect::Protection;
+pub use snapshot::SNAPSHOT_VERSION;
+pub use stream::Stream;
+pub use trace::{Trace, TraceFilter};
+use assembler::Source;
+use flight::FlightRecorder;
+use limits::Usage;
+#[cfg(feature = "coroutines")]
+use heap::Heap;
+
+// ASCII character codes
+pub const ASC_TAB: u8 = 8;
+pub const ASC_LF: u8 = 10;
+pub const ASC_FF: u8 = 12;
+pub const ASC_CR: u8 = 13;
+pub const ASC_SPACE: u8 = 32;
+pub const ASC_DOLLAR: u8 = 36;
+pub const ASC_PERCENT: u8 = 37;
+pub const ASC_PLUS: u8 = 43;
+pub const ASC_MINUS: u8 = 45;
+pub const ASC_SLASH: u8 = 47;
+
+pub const ASC_0: u8 = 48;
+pub const ASC_9: u8 = 57;
+pub const ASC_A: u8 = 65;
+pub const ASC_Z: u8 = 90;
+
+pub const ASC_L: u8 = 76;
+pub const ASC_S: u8 = 83;
+pub const ASC_J: u8 = 74;
+pub const ASC_T: u8 = 84;
+pub const ASC_F: u8 = 70;
+pub const ASC_K: u8 = 75;
+pub const ASC_X: u8 = 88;
+pub const ASC_C: u8 = 67;
+pub const ASC_D: u8 = 68;
+pub const ASC_G: u8 = 71;
+pub const ASC_I: u8 = 73;
+pub const ASC_P: u8 = 80;
+pub const ASC_O: u8 = 79;
+pub const ASC_N: u8 = 78;
+
+/// A machine word: 16 bits, or 32 with the `word32` feature.
+#[cfg(not(feature = "word32"))]
+pub type Word = i16;
+#[cfg(feature = "word32")]
+pub type Word = i32;
+/// A word taken as an address or bit pattern.
+#[cfg(not(feature = "word32"))]
+pub type UWord = u16;
+#[cfg(feature = "word32")]
+pub type UWord = u32;
+
+// Default memory configuration (see `Config`)
+pub const PROGSTART: usize = 401;
+pub const WORDCOUNT: usize = 19900;
+
+// Instruction encoding
+pub const FN_BITS: Word = 8;
+pub const FN_MASK: Word = 255;
+pub const F0_L: Word = 0;
+pub const F1_S: Word = 1;
+pub const F2_A: Word = 2;
+pub const F3_J: Word = 3;
+pub const F4_T: Word = 4;
+pub const F5_F: Word = 5;
+pub const F6_K: Word = 6;
+pub const F7_X: Word = 7;
+pub const FI_BIT: Word = 1 << 3;
+pub const FP_BIT: Word = 1 << 4;
+pub const FD_BIT: Word = 1 << 5;
+
+// K-codes (system calls)
+pub const K01_START: Word = 1;
+pub const K04_BACKTRACE: Word = 4;
+pub const K11_SELECTINPUT: Word = 11;
+pub const K12_SELECTOUTPUT: Word = 12;
+pub const K13_RDCH: Word = 13;
+pub const K14_WRCH: Word = 14;
+pub const K16_INPUT: Word = 16;
+pub const K17_OUTPUT: Word = 17;
+pub const K30_STOP: Word = 30;
+pub const K31_LEVEL: Word = 31;
+pub const K32_LONGJUMP: Word = 32;
+pub const K40_APTOVEC: Word = 40;
+pub const K41_FINDOUTPUT: Word = 41;
+pub const K42_FINDINPUT: Word = 42;
+pub const K46_ENDREAD: Word = 46;
+pub const K47_ENDWRITE: Word = 47;
+pub const K60_WRITES: Word = 60;
+pub const K62_WRITEN: Word = 62;
+pub const K63_NEWLINE: Word = 63;
+pub const K64_NEWPAGE: Word = 64;
+pub const K66_PACKSTRING: Word = 66;
+pub const K67_UNPACKSTRING: Word = 67;
+pub const K68_WRITED: Word = 68;
+pub const K70_READN: Word = 70;
+pub const K71_TERMINATOR: Word = 71;
+pub const K75_WRITEHEX: Word = 75;
+pub const K76_WRITEF: Word = 76;
+pub const K77_WRITEOCT: Word = 77;
+pub const K85_GETBYTE: Word = 85;
+pub const K86_PUTBYTE: Word = 86;
+pub const K87_GETVEC: Word = 87;
+pub const K88_FREEVEC: Word = 88;
+pub const K90_CHANGECO: Word = 90;
+
+pub const ENDSTREAMCH: Word = -1;
+pub const BYTESPERWORD: usize = size_of::<Word>();
+
+/// Registers of the running program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Regs {
+    pub pc: UWord,
+    pub sp: UWord,
+    pub a: Word,
+    pub b: Word,
+}
+
+/// What to do when `+`, `-` or `*` in the program, or a number read by
+/// READN or the assembler, does not fit in a word.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowCheck {
+    /// Wrap silently, as BCPL does.
+    #[default]
+    Off,
+    /// Wrap and write a warning naming PC and the operands to the stream
+    /// given to `BcplState::set_warnings`.
+    Warn,
+    /// Stop with `VmError::Overflow`.
+    Stop,
+}
+
+// Whether the decimal number `digits` (negated if `neg`) fits in a word.
+pub(crate) fn fits_word(digits: &str, neg: bool) -> bool {
+    let limit = if neg { Word::MIN.unsigned_abs() } else { Word::MAX as UWord };
+    let digits = digits.trim_start_matches('0');
+    digits.is_empty() || digits.parse::<UWord>().is_ok_and(|n| n <= limit)
+}
+
+// Global state
+pub struct BcplState {
+    m: Vec<Word>,
+    progstart: usize,
+    lomem: usize,
+    himem: usize,
+    regs: Regs,
+    #[cfg(feature = "coroutines")]
+    heap: Heap,
+    cis: usize,
+    cos: usize,
+    sysin: usize,
+    sysprint: usize,
+    cp: usize,
+    ch: Word,
+    src: Source,
+    // Label definitions of each section loaded, as (label, address)
+    section_labels: Vec<Vec<(usize, usize)>>,
+    listing: Option<Box<dyn Stream>>,
+    warnings: Option<Box<dyn Stream>>,
+    trace: Option<Trace>,
+    profile: Option<Profile>,
+    limits: Limits,
+    usage: Usage,
+    files: Vec<Option<Box<dyn Stream>>>,
+    devices: Vec<(String, Box<dyn Stream>)>,
+    kcodes: Vec<Option<KHandler>>,
+    #[cfg(feature = "coroutines")]
+    co_debug: bool,
+    arithmetic_traps: bool,
+    overflow_check: OverflowCheck,
+    protection: Protection,
+    // Addresses whose stores `protection` checks
+    guarded: Range<usize>,
+    // Which words of the code area hold instructions rather than data
+    code_words: Vec<bool>,
+    // Globals still holding the entry a G directive gave them
+    entry_globals: Vec<bool>,
+    // Globals given a value by a G directive or by storing their own
+    // number; any other store leaves m[g] != g, which is as telling.
+    assigned: Vec<bool>,
+    // PC and SP of the K instruction whose handler is running
+    kcode_at: (UWord, UWord),
+    // Header names of globals, from `load_symbols`
+    global_names: Vec<Option<String>>,
+    journal: Option<Journal>,
+    // Host K-codes whose results are journaled, by number
+    nondeterministic: Vec<bool>,
+    flight: FlightRecorder,
+}
+
+impl Default for BcplState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BcplState {
+    /// Creates a machine with the default geometry, the global vector
+    /// seeded and the start-up code in place, ready for `loadcode`.
+    pub fn new() -> Self {
+        Self::with_config(&Config::default()).expect("default configuration is valid")
+    }
+
+    /// Creates a machine with the memory geometry in `config`.
+    pub fn with_config(config: &Config) -> Result<Self, VmError> {
+        config.validate()?;
+        let mut state = BcplState::blank(config);
+        state.init();
+        kcode::register_builtins(&mut state);
+        Ok(state)
+    }
+
+    fn blank(config: &Config) -> Self {
+        let m: Vec<Word> = vec![0; config.wordcount];
+
+        BcplState {
+            m,
+            progstart: config.progstart,
+            lomem: 0,
+            himem: config.wordcount - 1,
+            regs: Regs::default(),
+            #[cfg(feature = "coroutines")]
+            heap: Heap::new(config.wordcount),
+            cis: 1,
+            cos: 2,
+            sysin: 1,
+            sysprint: 2,
+            cp: 0,
+            ch: 0,
+            src: Source::default(),
+            section_labels: Vec::new(),
+            listing: None,
+            warnings: None,
+            trace: None,
+            profile: None,
+            limits: Limits::default(),
+            usage: Usage::default(),
+            files: vec![None, Some(Box::new(stream::Stdin)), Some(Box::new(stream::Stdout))],
+            devices: Vec::new(),
+            kcodes: Vec::new(),
+            #[cfg(feature = "coroutines")]
+            co_debug: false,
+            arithmetic_traps: false,
+            overflow_check: OverflowCheck::Off,
+            protection: Protection::default(),
+            guarded: 0..0,
+            code_words: Vec::new(),
+            entry_globals: vec![false; config.progstart],
+            assigned: vec![false; config.progstart],
+            kcode_at: (0, 0),
+            global_names: vec![None; config.progstart],
+            journal: None,
+            nondeterministic: Vec::new(),
+            flight: FlightRecorder::new(0),
+        }
+    }
+
+    fn load(&self, addr: usize) -> Result<Word, VmError> {
+        self.m
+            .get(addr)
+            .copied()
+            .ok_or_else(|| VmError::bad_access(Access::Load, addr))
+    }
+
+    // Stores made by K-code handlers are charged to the K instruction.
+    fn store(&mut self, addr: usize, val: Word) -> Result<(), VmError> {
+        self.store_from(addr, val, self.kcode_at)
+    }
+
+    // A store by the instruction at PC and SP `at`.
+    #[inline(always)]
+    fn store_from(&mut self, addr: usize, val: Word, at: (UWord, UWord)) -> Result<(), VmError> {
+        if self.guards(addr) {
+            self.guarded_store(addr, val, at)?;
+        }
+        if val as UWord as usize == addr {
+            self.stored_own_number(addr);
+        }
+        match self.m.get_mut(addr) {
+            Some(w) => {
+                *w = val;
+                Ok(())
+            }
+            None => Err(VmError::bad_access(Access::Store, addr)),
+        }
+    }
+
+    // A global that holds its own number after a store has still been set.
+    #[cold]
+    fn stored_own_number(&mut self, addr: usize) {
+        if let Some(assigned) = self.assigned.get_mut(addr) {
+            *assigned = true;
+        }
+    }
+
+    // Whether global `g` still has the value `init` gave it and was never
+    // set: a call through it is then a K-code call or a missing definition.
+    fn untouched(&self, g: usize) -> bool {
+        g < self.progstart && self.m[g] == g as Word && !self.assigned[g]
+    }
+
+    // The global that the K instruction at `pc` called through, when the
+    // instruction before it loaded A from that global (`LIGn K`) and the
+    // global still holds `a`.
+    fn called_through(&self, pc: usize, a: Word) -> Option<usize> {
+        let load = (1..=2)
+            .rev()
+            .filter_map(|n| self.instruction_at(pc.checked_sub(n)?).filter(|i| i.words() == n))
+            .next()?;
+        let g = load.d as UWord as usize;
+        (load.op == F0_L && load.indirect && !load.p && g < self.progstart && self.m[g] == a)
+            .then_some(g)
+    }
+
+    // Bytes are packed little-endian: byte 0 of a string is the low byte of
+    // its first word.
+    fn get_byte(&self, byte_idx: usize) -> Result<u8, VmError> {
+        let val = self.load(byte_idx / BYTESPERWORD)?;
+        Ok(val.to_le_bytes()[byte_idx % BYTESPERWORD])
+    }
+
+    fn set_byte(&mut self, byte_idx: usize, val: u8) -> Result<(), VmError> {
+        let word_idx = byte_idx / BYTESPERWORD;
+        let mut bytes = self.load(word_idx)?.to_le_bytes();
+        bytes[byte_idx % BYTESPERWORD] = val;
+        self.store(word_idx, Word::from_le_bytes(bytes))
+    }
+
+    fn cstr(&self, s_ptr: usize) -> Result<String, VmError> {
+        let byte_idx = s_ptr * BYTESPERWORD;
+        let len = self.get_byte(byte_idx)? as usize;
+        let mut result = String::with_capacity(len);
+        for i in 0..len {
+            result.push(self.get_byte(byte_idx + 1 + i)? as char);
+        }
+        Ok(result)
+    }
+
+    fn openfile(&mut self, filename: &str, mode: &str) -> usize {
+        if filename.eq_ignore_ascii_case("SYSIN") {
+            return self.sysin;
+        }
+        if filename.eq_ignore_ascii_case("SYSPRINT") {
+            return self.sysprint;
+        }
+
+        if let Some(i) = self
+            .devices
+            .iter()
+            .position(|(name, _)| name.eq_ignore_ascii_case(filename))
+        {
+            let (_, device) = self.devices.remove(i);
+            return self.add_stream(device);
+        }
+
+        let handle: Box<dyn Stream> = if mode == "r" {
+            if let Ok(file) = File::open(filename) {
+                Box::new(stream::FileInput::new(filename, file, 0))
+            } else if let Ok(file) = File::open(filename.to_lowercase()) {
+                Box::new(stream::FileInput::new(&filename.to_lowercase(), file, 0))
+            } else {
+                return 0;
+            }
+        } else if let Ok(file) = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(filename)
+        {
+            Box::new(stream::FileOutput::new(filename, file, 0))
+        } else {
+            return 0;
+        };
+
+        self.add_stream(handle)
+    }
+
+    fn add_stream(&mut self, stream: Box<dyn Stream>) -> usize {
+        self.files.push(Some(stream));
+        self.files.len() - 1
+    }
+
+    fn output(&mut self) -> Option<&mut Box<dyn Stream>> {
+        self.files.get_mut(self.cos).and_then(Option::as_mut)
+    }
+
+    fn findinput(&mut self, fn_ptr: usize) -> Result<usize, VmError> {
+        let filename = self.cstr(fn_ptr)?;
+        let first = self.files.len();
+        match self.openfile(&filename, "r") {
+            f if f >= first => self.check_streams(f),
+            f => Ok(f),
+        }
+    }
+
+    fn findoutput(&mut self, fn_ptr: usize) -> Result<usize, VmError> {
+        let filename = self.cstr(fn_ptr)?;
+        let first = self.files.len();
+        match self.openfile(&filename, "w") {
+            f if f >= first => self.check_streams(f),
+            f => Ok(f),
+        }
+    }
+
+    fn endread(&mut self) {
+        if self.cis != self.sysin && self.cis < self.files.len() {
+            self.files[self.cis] = None;
+            self.cis = self.sysin;
+        }
+    }
+
+    fn endwrite(&mut self) {
+        if self.cos != self.sysprint && self.cos < self.files.len() {
+            if let Some(w) = self.output() {
+                w.flush();
+            }
+            self.files[self.cos] = None;
+            self.cos = self.sysprint;
+        }
+    }
+
+    /// Reads a character from the current input stream.
+    pub fn rdch(&mut self) -> Word {
+        if self.journal.is_some() {
+            return self.journal_rdch();
+        }
+        self.read_ch()
+    }
+
+    fn read_ch(&mut self) -> Word {
+        let c = match self.files.get_mut(self.cis) {
+            Some(Some(input)) => input.read_byte(),
+            _ => None,
+        };
+
+        match c {
+            Some(ASC_CR) => ASC_LF as Word,
+            Some(c) => c as Word,
+            None => ENDSTREAMCH,
+        }
+    }
+
+    /// Writes a character to the current output stream.
+    pub fn wrch(&mut self, c: Word) {
+        if c == ASC_LF as Word {
+            self.newline();
+        } else if self.charge_output()
+            && let Some(out) = self.output()
+        {
+            out.write_bytes(&[c as u8]);
+        }
+    }
+
+    fn newline(&mut self) {
+        if !self.charge_output() {
+            return;
+        }
+        if let Some(out) = self.output() {
+            out.write_bytes(b"\n");
+            out.end_line();
+        }
+    }
+
+    fn writes(&mut self, s_ptr: usize) -> Result<(), VmError> {
+        let byte_idx = s_ptr * BYTESPERWORD;
+        let len = self.get_byte(byte_idx)? as usize;
+        for i in 0..len {
+            let c = self.get_byte(byte_idx + 1 + i)?;
+            self.wrch(c as Word);
+        }
+        Ok(())
+    }
+
+    fn writed(&mut self, n: Word, d: Word) {
+        let s = format!("{}", n);
+        let padding = if d as usize > s.len() {
+            d as usize - s.len()
+        } else {
+            0
+        };
+        for _ in 0..padding {
+            self.wrch(ASC_SPACE as Word);
+        }
+        for c in s.bytes() {
+            self.wrch(c as Word);
+        }
+    }
+
+    fn writen(&mut self, n: Word) {
+        self.writed(n, 0);
+    }
+
+    // `at` is the (PC, SP) reported if the number overflows.
+    fn readn(&mut self, at: (UWord, UWord)) -> Result<Word, VmError> {
+        let mut sum: Word = 0;
+        let mut digits = String::new();
+        let mut neg = false;
+
+        loop {
+            self.ch = self.rdch();
+            if self.ch != ASC_SPACE as Word
+                && self.ch != ASC_LF as Word
+                && self.ch != ASC_TAB as Word
+            {
+                break;
+            }
+        }
+
+        if self.ch == ASC_MINUS as Word {
+            neg = true;
+            self.ch = self.rdch();
+        } else if self.ch == ASC_PLUS as Word {
+            self.ch = self.rdch();
+        }
+
+        while self.ch >= ASC_0 as Word && self.ch <= ASC_9 as Word {
+            sum = sum.wrapping_mul(10).wrapping_add(self.ch - ASC_0 as Word);
+            digits.push(self.ch as u8 as char);
+            self.ch = self.rdch();
+        }
+
+        self.store(K71_TERMINATOR as usize, self.ch)?;
+        if !fits_word(&digits, neg) {
+            let sign = if neg { "-" } else { "" };
+            self.overflow(format!("READING {}{}", sign, digits), at.0, at.1)?;
+        }
+        Ok(if neg { sum.wrapping_neg() } else { sum })
+    }
+
+    fn writeoct(&mut self, n: UWord, d: Word) {
+        if d > 1 {
+            self.writeoct(n >> 3, d - 1);
+        }
+        let digit = (n & 7) as u8;
+        self.wrch((b'0' + digit) as Word);
+    }
+
+    fn writehex(&mut self, n: UWord, d: Word) {
+        if d > 1 {
+            self.writehex(n >> 4, d - 1);
+        }
+        let digit = (n & 15) as u8;
+        let c = if digit < 10 {
+            b'0' + digit
+        } else {
+            b'A' + digit - 10
+        };
+        self.wrch(c as Word);
+    }
+
+    fn decval(&self, c: u8) -> Word {
+        if (ASC_0..=ASC_9).contains(&c) {
+            (c - ASC_0) as Word
+        } else if (ASC_A..=ASC_Z).contains(&c) {
+            (c - ASC_A + 10) as Word
+        } else {
+            0
+        }
+    }
+
+    fn writef(&mut self, v_ptr: usize) -> Result<(), VmError> {
+        let fmt_ptr = self.load(v_ptr)? as UWord as usize;
+        let mut v_idx = v_ptr + 1;
+        let byte_idx = fmt_ptr * BYTESPERWORD;
+        let len = self.get_byte(byte_idx)? as usize;
+        let mut ss = 1;
+
+        while ss <= len {
+            let c = self.get_byte(byte_idx + ss)?;
+            ss += 1;
+            if c != ASC_PERCENT {
+                self.wrch(c as Word);
+            } else {
+                let c = self.get_byte(byte_idx + ss)?;
+                ss += 1;
+                match c {
+                    b'S' => {
+                        self.writes(self.load(v_idx)? as UWord as usize)?;
+                        v_idx += 1;
+                    }
+                    b'C' => {
+                        self.wrch(self.load(v_idx)?);
+                        v_idx += 1;
+                    }
+                    b'O' => {
+                        let val = self.load(v_idx)? as UWord;
+                        let d = self.decval(self.get_byte(byte_idx + ss)?);
+                        ss += 1;
+                        self.writeoct(val, d);
+                        v_idx += 1;
+                    }
+                    b'X' => {
+                        let val = self.load(v_idx)? as UWord;
+                        let d = self.decval(self.get_byte(byte_idx + ss)?);
+                        ss += 1;
+                        self.writehex(val, d);
+                        v_idx += 1;
+                    }
+                    b'I' => {
+                        let val = self.load(v_idx)?;
+                        let d = self.decval(self.get_byte(byte_idx + ss)?);
+                        ss += 1;
+                        self.writed(val, d);
+                        v_idx += 1;
+                    }
+                    b'N' => {
+                        self.writen(self.load(v_idx)?);
+                        v_idx += 1;
+                    }
+                    _ => {
+                        self.wrch(c as Word);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn packstring(&mut self, v_ptr: usize, s_ptr: usize) -> Result<Word, VmError> {
+        let len = self.load(v_ptr)? as UWord as usize;
+        let n = len / BYTESPERWORD;
+
+        self.store(s_ptr + n, 0)?;
+
+        for i in 0..=len {
+            let c = self.load(v_ptr + i)?;
+            self.set_byte(s_ptr * BYTESPERWORD + i, (c & 0xFF) as u8)?;
+        }
+
+        Ok(n as Word)
+    }
+
+    fn unpackstring(&mut self, s_ptr: usize, v_ptr: usize) -> Result<(), VmError> {
+        let byte_idx = s_ptr * BYTESPERWORD;
+        let len = self.get_byte(byte_idx)? as usize;
+
+        for i in 0..=len {
+            let c = self.get_byte(byte_idx + i)?;
+            self.store(v_ptr + i, c as Word)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `msg` as a line on SYSPRINT, the way icint reports errors.
+    pub fn report(&mut self, msg: &str) {
+        self.cos = self.sysprint;
+        if let Some(out) = self.output() {
+            out.write_bytes(msg.as_bytes());
+            out.write_bytes(b"\n");
+            out.flush();
+        }
+    }
+
+    /// Runs the loaded program from the start of the code area until it
+    /// calls `STOP` or `FINISH`, and returns the stop code.
+    pub fn interpret(&mut self) -> Result<Word, VmError> {
+        self.reset_registers();
+        self.run()
+    }
+
+    /// Sets the registers for a run from the start of the code area, with
+    /// the stack just above the loaded code.
+    pub fn reset_registers(&mut self) {
+        self.regs = Regs {
+            pc: self.progstart as UWord,
+            sp: self.lomem as UWord,
+            a: 0,
+            b: 0,
+        };
+        self.usage = Usage::default();
+        self.clear_flight();
+    }
+
+    /// Runs from the current registers until the program stops. After an
+    /// error the registers are those of the failing instruction.
+    pub fn run(&mut self) -> Result<Word, VmError> {
+        if let Some(code) = self.run_observed()? {
+            return Ok(code);
+        }
+        let mut r = self.regs;
+        loop {
+            let slice = self.check_limits().map_err(|e| {
+                self.regs = r;
+                e.at(r.pc as usize, r.sp as usize)
+            })?;
+            // Without an instruction or time limit there is nothing to count.
+            let mut left = slice;
+            let result = match (slice == u64::MAX, self.flight.is_on()) {
+                (true, false) => self.run_slice::<false, false>(&mut r, &mut left),
+                (false, false) => self.run_slice::<true, false>(&mut r, &mut left),
+                (true, true) => self.run_slice::<false, true>(&mut r, &mut left),
+                (false, true) => self.run_slice::<true, true>(&mut r, &mut left),
+            };
+            self.usage.executed += slice - left;
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+
+    // Executes instructions until the program stops or, if COUNTED, `left`
+    // runs out. Returns the result if the program stopped. RECORDED keeps
+    // the flight record.
+    #[inline(always)]
+    fn run_slice<const COUNTED: bool, const RECORDED: bool>(
+        &mut self,
+        regs: &mut Regs,
+        left: &mut u64,
+    ) -> Option<Result<Word, VmError>> {
+        let mut r = *regs;
+        let result = loop {
+            if COUNTED {
+                if *left == 0 {
+                    break None;
+                }
+                *left -= 1;
+            }
+            let (pc, sp) = (r.pc, r.sp);
+            match self.execute_with::<RECORDED>(&mut r) {
+                Ok(None) => {}
+                Ok(Some(code)) => {
+                    self.regs = r;
+                    break Some(Ok(code));
+                }
+                Err(e) => {
+                    self.regs = Regs { pc, sp, ..r };
+                    break Some(Err(e.at(pc as usize, sp as usize)));
+                }
+            }
+        };
+        *regs = r;
+        result
+    }
+
+    /// Executes the instruction at `pc`. Returns the stop code if the
+    /// program ended.
+    pub fn step(&mut self) -> Result<Option<Word>, VmError> {
+        self.check_limits()
+            .map_err(|e| e.at(self.regs.pc as usize, self.regs.sp as usize))?;
+        self.usage.executed += 1;
+        let mut r = self.regs;
+        let result = if self.observed() {
+            self.execute_observed(&mut r)
+        } else {
+            self.execute_any(&mut r)
+        };
+        if result.is_ok() {
+            self.regs = r;
+        }
+        result.map_err(|e| e.at(self.regs.pc as usize, self.regs.sp as usize))
+    }
+
+    // Runs while a trace or profile needs to see each instruction. Returns
+    // the stop code if the program stopped.
+    fn run_observed(&mut self) -> Result<Option<Word>, VmError> {
+        let mut r = self.regs;
+        while self.observed() {
+            let (pc, sp) = (r.pc, r.sp);
+            if let Err(e) = self.check_limits() {
+                self.regs = r;
+                return Err(e.at(pc as usize, sp as usize));
+            }
+            self.usage.executed += 1;
+            match self.execute_observed(&mut r) {
+                Ok(None) => {}
+                Ok(Some(code)) => {
+                    self.regs = r;
+                    return Ok(Some(code));
+                }
+                Err(e) => {
+                    self.regs = Regs { pc, sp, ..r };
+                    return Err(e.at(pc as usize, sp as usize));
+                }
+            }
+        }
+        self.regs = r;
+        Ok(None)
+    }
+
+    fn observed(&self) -> bool {
+        self.tracing() || self.profile.is_some()
+    }
+
+    fn execute_observed(&mut self, r: &mut Regs) -> Result<Option<Word>, VmError> {
+        let event = self.profile_before(r);
+        let result = if self.tracing() {
+            self.execute_traced(r)
+        } else {
+            self.execute_any(r)
+        };
+        if let Some(event) = event
+            && result.is_ok()
+        {
+            self.profile_after(event, r);
+        }
+        result
+    }
+
+    pub fn registers(&self) -> Regs {
+        self.regs
+    }
+
+    pub fn set_registers(&mut self, regs: Regs) {
+        self.regs = regs;
+    }
+
+    // Executes one instruction. Returns the stop code once the program ends.
+    #[inline(always)]
+    fn execute(&mut self, r: &mut Regs) -> Result<Option<Word>, VmError> {
+        self.execute_with::<false>(r)
+    }
+
+    // `execute` for the slower paths, which check for the flight recorder
+    // on each instruction.
+    pub(crate) fn execute_any(&mut self, r: &mut Regs) -> Result<Option<Word>, VmError> {
+        if self.flight.is_on() {
+            self.execute_with::<true>(r)
+        } else {
+            self.execute(r)
+        }
+    }
+
+    // `execute`, noting the instruction in the flight record if RECORDED.
+    #[inline(always)]
+    fn execute_with<const RECORDED: bool>(
+        &mut self,
+        r: &mut Regs,
+    ) -> Result<Option<Word>, VmError> {
+        let pc = r.pc;
+        let w = self
+            .m
+            .get(r.pc as usize)
+            .copied()
+            .ok_or_else(|| VmError::bad_access(Access::Pc, r.pc as usize))? as UWord;
+        r.pc = r.pc.wrapping_add(1);
+
+        // d is unsigned just like in C: register word d
+        let mut d: UWord = if w & (FD_BIT as UWord) != 0 {
+            let val = self
+                .m
+                .get(r.pc as usize)
+                .copied()
+                .ok_or_else(|| VmError::bad_access(Access::Pc, r.pc as usize))?;
+            r.pc = r.pc.wrapping_add(1);
+            val as UWord
+        } else {
+            w >> FN_BITS
+        };
+        if RECORDED {
+            self.record_flight(pc, w, d, r);
+        }
+
+        if w & (FP_BIT as UWord) != 0 {
+            d = d.wrapping_add(r.sp);
+        }
+        if w & (FI_BIT as UWord) != 0 {
+            d = self.load(d as usize)? as UWord;
+        }
+
+        match w & F7_X as UWord {
+            0 => { // F0_L
+                r.b = r.a;
+                r.a = d as Word;
+            }
+            1 => { // F1_S
+                self.store_from(d as usize, r.a, (pc, r.sp))?;
+            }
+            2 => { // F2_A
+                let (sum, over) = r.a.overflowing_add(d as Word);
+                if over && self.overflow_check != OverflowCheck::Off {
+                    self.overflowed(pc, r.sp, r.a, '+', d as Word)?;
+                }
+                r.a = sum;
+            }
+            3 => { // F3_J
+                r.pc = d;
+            }
+            4 if r.a != 0 => { // F4_T
+                r.pc = d;
+            }
+            5 if r.a == 0 => { // F5_F
+                r.pc = d;
+            }
+            6 => { // F6_K
+                let d_addr = d.wrapping_add(r.sp);
+                if (r.a as UWord as usize) < self.progstart {
+                    self.kcode_at = (pc, r.sp);
+                    let mut call = KCall { pc: r.pc, sp: r.sp, a: r.a, d_addr };
+                    if let KResult::Stop(code) = self.call_kcode(&mut call)? {
+                        return Ok(Some(code));
+                    }
+                    r.pc = call.pc;
+                    r.sp = call.sp;
+                    r.a = call.a;
+                } else {
+                    let d_idx = d_addr as usize;
+                    if d_idx + 1 >= self.m.len() {
+                        return Err(VmError::bad_access(Access::Frame, d_idx));
+                    }
+                    if self.guards(d_idx) || self.guards(d_idx + 1) {
+                        self.guarded_store(d_idx, r.sp as Word, (pc, r.sp))?;
+                        self.guarded_store(d_idx + 1, r.pc as Word, (pc, r.sp))?;
+                    }
+                    self.m[d_idx] = r.sp as Word;
+                    self.m[d_idx + 1] = r.pc as Word;
+                    r.sp = d_addr;
+                    r.pc = r.a as UWord;
+                }
+            }
+            7 => { // F7_X
+                let (a, b) = (r.a, r.b);
+                match d {
+                    1 => r.a = self.load(a as UWord as usize)?,
+                    2 => r.a = a.wrapping_neg(),
+                    3 => r.a = !a,
+                    4 => {
+                        r.pc = self.load(r.sp as usize + 1)? as UWord;
+                        r.sp = self.load(r.sp as usize)? as UWord;
+                    }
+                    5 => {
+                        let (product, over) = b.overflowing_mul(a);
+                        if over && self.overflow_check != OverflowCheck::Off {
+                            self.overflowed(pc, r.sp, b, '*', a)?;
+                        }
+                        r.a = product;
+                    }
+                    6 if a == 0 => r.a = self.divide_by_zero()?,
+                    6 => r.a = b.wrapping_div(a),
+                    7 if a == 0 => r.a = self.divide_by_zero()?,
+                    7 => r.a = b.wrapping_rem(a),
+                    8 => {
+                        let (sum, over) = b.overflowing_add(a);
+                        if over && self.overflow_check != OverflowCheck::Off {
+                            self.overflowed(pc, r.sp, b, '+', a)?;
+                        }
+                        r.a = sum;
+                    }
+                    9 => {
+                        let (difference, over) = b.overflowing_sub(a);
+                        if over && self.overflow_check != OverflowCheck::Off {
+                            self.overflowed(pc, r.sp, b, '-', a)?;
+                        }
+                        r.a = difference;
+                    }
+                    10 => r.a = if b == a { -1 } else { 0 },
+                    11 => r.a = if b != a { -1 } else { 0 },
+                    12 => r.a = if b < a { -1 } else { 0 },
+                    13 => r.a = if b >= a { -1 } else { 0 },
+                    14 => r.a = if b > a { -1 } else { 0 },
+                    15 => r.a = if b <= a { -1 } else { 0 },
+                    16 | 17 if a as UWord >= Word::BITS as UWord => r.a = self.bad_shift(a)?,
+                    16 => r.a = b << a,
+                    17 => r.a = ((b as UWord) >> a) as Word,
+                    18 => r.a &= b,
+                    19 => r.a |= b,
+                    20 => r.a ^= b,
+                    21 => r.a = b ^ !a,
+                    22 => return Ok(Some(0)),
+                    23 => {
+                        let mut v_idx = r.pc as usize;
+                        let mut count = self.load(v_idx)?;
+                        v_idx += 1;
+                        r.pc = self.load(v_idx)? as UWord;
+                        v_idx += 1;
+
+                        while count > 0 {
+                            if a == self.load(v_idx)? {
+                                r.pc = self.load(v_idx + 1)? as UWord;
+                                break;
+                            }
+                            v_idx += 2;
+                            count -= 1;
+                        }
+                    }
+                    _ => {
+                        return Err(VmError::UnknownExec {
+                            code: d as Word,
+                            at: Context::default(),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Installs `handler` as K-code `n`, replacing any existing handler
+    /// (including a built-in one). `n` must be below the start of the code
+    /// area (`PROGSTART` by default).
+    pub fn register_kcode<F>(&mut self, n: Word, handler: F)
+    where
+        F: FnMut(&mut BcplState, &mut KCall) -> Result<KResult, VmError> + 'static,
+    {
+        let n = n as UWord as usize;
+        assert!(n < self.progstart, "K-code {} is not below PROGSTART", n);
+        if self.kcodes.len() <= n {
+            self.kcodes.resize_with(n + 1, || None);
+        }
+        self.kcodes[n] = Some(Box::new(handler));
+    }
+
+    /// Removes K-code `n`; calling it then fails with `VmError::UnknownCall`,
+    /// or `VmError::UndefinedGlobal` if the call was through global `n`
+    /// and the program never set it.
+    pub fn unregister_kcode(&mut self, n: Word) {
+        if let Some(slot) = self.kcodes.get_mut(n as UWord as usize) {
+            *slot = None;
+        }
+    }
+
+    fn call_kcode(&mut self, call: &mut KCall) -> Result<KResult, VmError> {
+        let n = call.a;
+        if self.replays(n) {
+            call.a = self.replayed_result(call)?;
+            return Ok(KResult::Continue);
+        }
+        let i = n as UWord as usize;
+        let handler = self.kcodes.get_mut(i).and_then(Option::take);
+        let Some(mut handler) = handler else {
+            let global = self.called_through(self.kcode_at.0 as usize, n).unwrap_or(i);
+            if self.untouched(global) {
+                let name = self.global_name(global).map(String::from);
+                return Err(VmError::UndefinedGlobal { global, name, at: Context::default() });
+            }
+            return Err(VmError::UnknownCall { code: n, at: Context::default() });
+        };
+        let result = handler(self, call).and_then(|k| {
+            self.check_output()?;
+            self.check_journal()?;
+            if self.journaled(n) {
+                self.journal_result(n, call)?;
+            }
+            Ok(k)
+        });
+        // The handler is out of the table while it runs; put it back unless
+        // it registered a replacement for itself.
+        let slot = &mut self.kcodes[i];
+        if slot.is_none() {
+            *slot = Some(handler);
+        }
+        result
+    }
+
+    /// Assembles the INTCODE file `filename` into memory after any
+    /// previously loaded sections.
+    pub fn loadcode(&mut self, filename: &str) -> Result<(), VmError> {
+        let f = self.openfile(filename, "r");
+        if f == 0 {
+            return Err(VmError::NoIcfile(filename.to_string()));
+        }
+        self.cis = f;
+        let result = self.assemble(filename);
+        self.endread();
+        result
+    }
+
+    /// Assembles INTCODE held in memory, as `loadcode` does for a file.
+    pub fn load_str(&mut self, code: &str) -> Result<(), VmError> {
+        let input = stream::ReadStream(Cursor::new(code.as_bytes().to_vec()));
+        self.cis = self.add_stream(Box::new(input));
+        let result = self.assemble("<string>");
+        self.endread();
+        result
+    }
+
+    fn init(&mut self) {
+        for i in 0..self.progstart {
+            self.m[i] = i as Word;
+        }
+
+        // LIG1 K2 X22: call START, then FINISH
+        let start = [
+            F0_L | FI_BIT | (K01_START << FN_BITS),
+            F6_K | (2 << FN_BITS),
+            F7_X | (22 << FN_BITS),
+        ];
+        let p = self.progstart;
+        self.m[p..p + start.len()].copy_from_slice(&start);
+        self.lomem = p + start.len();
+        self.cp = 0;
+        #[cfg(feature = "coroutines")]
+        self.heap.reset();
+    }
+
+    /// Uses `filename` as SYSIN, as the `-i` option does.
+    pub fn pipeinput(&mut self, filename: &str) -> Result<(), VmError> {
+        let f = self.openfile(filename, "r");
+        if f == 0 {
+            return Err(VmError::NoInput(filename.to_string()));
+        }
+        self.cis = f;
+        self.sysin = f;
+        Ok(())
+    }
+
+    /// Uses `filename` as SYSPRINT, as the `-o` option does.
+    pub fn pipeoutput(&mut self, filename: &str) -> Result<(), VmError> {
+        let f = self.openfile(filename, "w");
+        if f == 0 {
+            return Err(VmError::NoOutput(filename.to_string()));
+        }
+        self.cos = f;
+        self.sysprint = f;
+        Ok(())
+    }
+
+    /// Uses `stream` as SYSIN.
+    pub fn set_sysin(&mut self, stream: Box<dyn Stream>) {
+        let f = self.add_stream(stream);
+        self.cis = f;
+        self.sysin = f;
+    }
+
+    /// Uses `stream` as SYSPRINT.
+    pub fn set_sysprint(&mut self, stream: Box<dyn Stream>) {
+        let f = self.add_stream(stream);
+        self.cos = f;
+        self.sysprint = f;
+    }
+
+    /// Writes an assembler listing to `stream` as code is loaded: each
+    /// INTCODE line with the address and words it produced, followed by the
+    /// label table of each section. `None` turns the listing off.
+    pub fn set_listing(&mut self, stream: Option<Box<dyn Stream>>) {
+        self.listing = stream;
+    }
+
+    /// Writes warnings to `stream`, a line each: overflow with
+    /// `OverflowCheck::Warn` and stores over entry globals with
+    /// `Protection::globals`. `None`, the default, drops them.
+    pub fn set_warnings(&mut self, stream: Option<Box<dyn Stream>>) {
+        self.warnings = stream;
+    }
+
+    // Writes the warning `text`, which says where it arose.
+    #[cold]
+    pub(crate) fn warn(&mut self, text: &str) {
+        if let Some(warnings) = &mut self.warnings {
+            warnings.write_bytes(text.as_bytes());
+            warnings.write_bytes(b"\n");
+        }
+    }
+
+    /// Makes `stream` available to the program under `name`: the next
+    /// `FINDINPUT` or `FINDOUTPUT` of that name (ignoring case) gets it
+    /// instead of a file.
+    pub fn attach_device(&mut self, name: &str, stream: Box<dyn Stream>) {
+        self.devices.push((name.to_string(), stream));
+    }
+
+    /// Flushes any buffered output streams.
+    pub fn flush(&mut self) {
+        for stream in self.files.iter_mut().flatten() {
+            stream.flush();
+        }
+        for out in [&mut self.listing, &mut self.warnings].into_iter().flatten() {
+            out.flush();
+        }
+        self.flush_trace();
+        self.flush_journal();
+    }
+
+    /// The whole word memory, globals first.
+    pub fn memory(&self) -> &[Word] {
+        &self.m
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [Word] {
+        &mut self.m
+    }
+
+    /// Global `n`, i.e. `m[n]`.
+    pub fn global(&self, n: usize) -> Word {
+        self.m[n]
+    }
+
+    /// Sets global `n` and counts it as defined. `n` must be below
+    /// `progstart`.
+    pub fn set_global(&mut self, n: usize, val: Word) {
+        assert!(n < self.progstart, "G{} is not below PROGSTART", n);
+        self.m[n] = val;
+        self.assigned[n] = true;
+    }
+
+    /// First free word after the loaded code.
+    pub fn lomem(&self) -> usize {
+        self.lomem
+    }
+
+    pub fn himem(&self) -> usize {
+        self.himem
+    }
+
+    /// Address of the first word of code; globals lie below it.
+    pub fn progstart(&self) -> usize {
+        self.progstart
+    }
+
+    /// Enables the coroutine state traces on stderr (`BCPL_CO_DEBUG`).
+    #[cfg(feature = "coroutines")]
+    pub fn set_co_debug(&mut self, on: bool) {
+        self.co_debug = on;
+    }
+
+    /// Makes division by zero and shifts by a negative count or one of
+    /// `Word::BITS` or more fault instead of giving 0.
+    pub fn set_arithmetic_traps(&mut self, on: bool) {
+        self.arithmetic_traps = on;
+    }
+
+    // The result of X6 or X7 with a zero divisor.
+    #[cold]
+    fn divide_by_zero(&self) -> Result<Word, VmError> {
+        if self.arithmetic_traps {
+            return Err(VmError::DivideByZero { at: Context::default() });
+        }
+        Ok(0)
+    }
+
+    // The result of X16 or X17 with a count outside 0..Word::BITS: every
+    // bit is shifted out.
+    #[cold]
+    fn bad_shift(&self, count: Word) -> Result<Word, VmError> {
+        if self.arithmetic_traps {
+            return Err(VmError::BadShift { count, at: Context::default() });
+        }
+        Ok(0)
+    }
+
+    /// Sets how overflow of `+`, `-`, `*` and of numbers read by READN or
+    /// the assembler is treated.
+    pub fn set_overflow_check(&mut self, check: OverflowCheck) {
+        self.overflow_check = check;
+    }
+
+    // Reports `left op right` overflowing at `pc`.
+    #[cold]
+    fn overflowed(&mut self, pc: UWord, sp: UWord, left: Word, op: char, right: Word) -> Result<(), VmError> {
+        self.overflow(format!("{} {} {}", left, op, right), pc, sp)
+    }
+
+    // Reports an overflow as the overflow check asks.
+    #[cold]
+    fn overflow(&mut self, expr: String, pc: UWord, sp: UWord) -> Result<(), VmError> {
+        let err = VmError::Overflow { expr, at: Context { pc: pc as usize, sp: sp as usize } };
+        match self.overflow_check {
+            OverflowCheck::Off => Ok(()),
+            OverflowCheck::Warn => {
+                self.warn(&format!("WARNING {}", err));
+                Ok(())
+            }
+            OverflowCheck::Stop => Err(err),
+        }
+    }
+
+    /// Reads the BCPL string at word address `s_ptr`.
+    pub fn string_at(&self, s_ptr: usize) -> Result<String, VmError> {
+        self.cstr(s_ptr)
+    }
+}
@@ -0,0 +1,110 @@
+// Resource limits for running untrusted programs. The instruction count and
+// the clock are checked between slices of the run loop, so the loop itself
+// only counts down; output is capped in `wrch` and checked after each
+// K-code, and streams are counted when FINDINPUT or FINDOUTPUT opens one.
+
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+/// Instructions between looks at the clock.
+const TIME_SLICE: u64 = 1 << 16;
+
+/// Caps on a run. `None` means no limit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions executed.
+    pub instructions: Option<u64>,
+    /// Wall-clock time from the first instruction.
+    pub time: Option<Duration>,
+    /// Bytes written by `wrch` (and so by every output K-code) to any stream.
+    pub output: Option<u64>,
+    /// Streams opened by FINDINPUT and FINDOUTPUT and not yet closed.
+    pub streams: Option<usize>,
+}
+
+/// The limit a run reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Instructions(u64),
+    Time(Duration),
+    Output(u64),
+    Streams(usize),
+}
+
+// Usage counted against the limits. Instructions are only counted while an
+// instruction or time limit is set, or a trace or profile sees each one.
+#[derive(Default)]
+pub(crate) struct Usage {
+    pub(crate) executed: u64,
+    pub(crate) started: Option<Instant>,
+    pub(crate) written: u64,
+}
+
+fn exceeded(limit: Limit) -> VmError {
+    VmError::LimitExceeded { limit, at: Context::default() }
+}
+
+impl BcplState {
+    /// Sets the limits for later runs. Usage counts from the next
+    /// `reset_registers`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    // Fails if the instruction or time limit has been reached; otherwise
+    // returns how many instructions may run before the next check.
+    pub(crate) fn check_limits(&mut self) -> Result<u64, VmError> {
+        let started = *self.usage.started.get_or_insert_with(Instant::now);
+        let mut slice = u64::MAX;
+        if let Some(max) = self.limits.instructions {
+            if self.usage.executed >= max {
+                return Err(exceeded(Limit::Instructions(max)));
+            }
+            slice = max - self.usage.executed;
+        }
+        if let Some(time) = self.limits.time {
+            if started.elapsed() >= time {
+                return Err(exceeded(Limit::Time(time)));
+            }
+            slice = slice.min(TIME_SLICE);
+        }
+        Ok(slice)
+    }
+
+    // Counts a byte of output. Returns false if it would go over the limit,
+    // in which case it is not written.
+    pub(crate) fn charge_output(&mut self) -> bool {
+        self.usage.written += 1;
+        self.limits.output.is_none_or(|max| self.usage.written <= max)
+    }
+
+    // Fails once output has gone over the limit.
+    pub(crate) fn check_output(&self) -> Result<(), VmError> {
+        match self.limits.output {
+            Some(max) if self.usage.written > max => Err(exceeded(Limit::Output(max))),
+            _ => Ok(()),
+        }
+    }
+
+    // Fails if opening stream `f` put the program over its stream limit, and
+    // closes it again.
+    pub(crate) fn check_streams(&mut self, f: usize) -> Result<usize, VmError> {
+        let Some(max) = self.limits.streams else {
+            return Ok(f);
+        };
+        // Entries 1 and 2 are the process's standard input and output.
+        let open = (self.files.iter().enumerate().skip(3))
+            .filter(|&(i, s)| s.is_some() && i != self.sysin && i != self.sysprint)
+            .count();
+        if open > max {
+            self.files[f] = None;
+            return Err(exceeded(Limit::Streams(max)));
+        }
+        Ok(f)
+    }
+}
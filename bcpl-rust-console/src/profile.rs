@@ -0,0 +1,285 @@
+// Procedure-level profiler. A K instruction with A at or above PROGSTART
+// enters a procedure, as does APTOVEC, and X4 returns from one, so a shadow
+// call stack can be kept beside the program's own. Each instruction is
+// charged to the address it was at and to the node of the call tree for the
+// current stack; the tree gives exclusive counts and collapsed stacks, and
+// inclusive counts are taken from the instruction total at entry and return.
+//
+// LONGJUMP drops the frames above the stack pointer it restores. CHANGECO
+// is not followed: a coroutine's procedures appear under the caller of
+// CALLCO or RESUMECO.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::*;
+
+/// Hot spots listed in the report.
+const HOT_SPOTS: usize = 20;
+
+struct Node {
+    // Procedure entry address (0 for the start-up code at the root)
+    entry: UWord,
+    parent: usize,
+    children: HashMap<UWord, usize>,
+    // Instructions executed with exactly this stack
+    count: u64,
+}
+
+#[derive(Default)]
+struct Procedure {
+    calls: u64,
+    inclusive: u64,
+    // Activations on the stack, and the total when the outermost began
+    depth: u32,
+    start: u64,
+}
+
+/// Instruction counts gathered while profiling.
+pub struct Profile {
+    total: u64,
+    hits: Vec<u64>,
+    nodes: Vec<Node>,
+    // Shadow stack of (node, SP of the frame)
+    stack: Vec<(usize, UWord)>,
+    procedures: HashMap<UWord, Procedure>,
+}
+
+// What the instruction about to execute means for the call stack.
+pub(crate) enum CallEvent {
+    Call(UWord),
+    Return,
+    Longjump,
+}
+
+impl Profile {
+    fn new(words: usize) -> Self {
+        let root = Node { entry: 0, parent: 0, children: HashMap::new(), count: 0 };
+        Profile {
+            total: 0,
+            hits: vec![0; words],
+            nodes: vec![root],
+            stack: Vec::new(),
+            procedures: HashMap::new(),
+        }
+    }
+
+    /// Instructions executed while profiling.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().map_or(0, |&(node, _)| node)
+    }
+
+    fn enter(&mut self, entry: UWord, sp: UWord) {
+        let parent = self.current();
+        let next = self.nodes.len();
+        let node = *self.nodes[parent].children.entry(entry).or_insert(next);
+        if node == next {
+            self.nodes.push(Node { entry, parent, children: HashMap::new(), count: 0 });
+        }
+        self.stack.push((node, sp));
+
+        let total = self.total;
+        let p = self.procedures.entry(entry).or_default();
+        p.calls += 1;
+        if p.depth == 0 {
+            p.start = total;
+        }
+        p.depth += 1;
+    }
+
+    fn leave(&mut self) {
+        let Some((node, _)) = self.stack.pop() else {
+            return;
+        };
+        let total = self.total;
+        if let Some(p) = self.procedures.get_mut(&self.nodes[node].entry) {
+            p.depth -= 1;
+            if p.depth == 0 {
+                p.inclusive += total - p.start;
+            }
+        }
+    }
+
+    // Procedure entries on the path from the root to `node`, outermost first.
+    fn path(&self, mut node: usize) -> Vec<UWord> {
+        let mut path = Vec::new();
+        while node != 0 {
+            path.push(self.nodes[node].entry);
+            node = self.nodes[node].parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+impl BcplState {
+    /// Starts counting instructions per procedure and per address, or stops
+    /// and discards the counts.
+    pub fn set_profiling(&mut self, on: bool) {
+        self.profile = on.then(|| Profile::new(self.m.len()));
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    // Charges the instruction at PC and says what it will do to the stack.
+    #[inline]
+    pub(crate) fn profile_before(&mut self, r: &Regs) -> Option<CallEvent> {
+        let profile = self.profile.as_mut()?;
+        let pc = r.pc as usize;
+        let w = *self.m.get(pc)?;
+        profile.total += 1;
+        profile.hits[pc] += 1;
+        let node = profile.current();
+        profile.nodes[node].count += 1;
+
+        match w & 7 {
+            F6_K if r.a as UWord as usize >= self.progstart => Some(CallEvent::Call(r.a as UWord)),
+            F6_K if r.a == K32_LONGJUMP => Some(CallEvent::Longjump),
+            // APTOVEC calls its first argument in a frame of its own
+            F6_K if r.a == K40_APTOVEC => {
+                let ins = self.instruction_at(pc)?;
+                let arg = r.sp.wrapping_add(ins.d as UWord).wrapping_add(2);
+                Some(CallEvent::Call(*self.m.get(arg as usize)? as UWord))
+            }
+            F7_X if self.instruction_at(pc).is_some_and(|ins| ins.d == 4) => {
+                Some(CallEvent::Return)
+            }
+            _ => None,
+        }
+    }
+
+    // Follows a call, return or long jump once it has executed.
+    pub(crate) fn profile_after(&mut self, event: CallEvent, r: &Regs) {
+        let Some(profile) = self.profile.as_mut() else {
+            return;
+        };
+        match event {
+            CallEvent::Call(entry) => profile.enter(entry, r.sp),
+            CallEvent::Return => profile.leave(),
+            CallEvent::Longjump => {
+                while profile.stack.last().is_some_and(|&(_, sp)| sp > r.sp) {
+                    profile.leave();
+                }
+            }
+        }
+    }
+
+    // A procedure is named by the global holding its entry, if any, and by
+    // the global's header name if symbols were loaded.
+    fn procedure_names(&self) -> HashMap<UWord, String> {
+        let mut names = HashMap::new();
+        if let Some(profile) = &self.profile {
+            for &entry in profile.procedures.keys() {
+                let name = match self.callee_name(entry as Word) {
+                    Some(name) => name.to_string(),
+                    None => match (0..self.progstart).find(|&g| self.m[g] as UWord == entry) {
+                        Some(g) => format!("G{}@{}", g, entry),
+                        None => entry.to_string(),
+                    },
+                };
+                names.insert(entry, name);
+            }
+        }
+        names.insert(0, "STARTUP".to_string());
+        names
+    }
+
+    /// Writes the profile report: calls and inclusive and exclusive
+    /// instruction counts per procedure, then the most executed addresses.
+    pub fn write_profile(&self, mut out: impl Write) -> io::Result<()> {
+        let Some(profile) = &self.profile else {
+            return Ok(());
+        };
+        let names = self.procedure_names();
+        let total = profile.total;
+        let percent = |n: u64| 100.0 * n as f64 / total.max(1) as f64;
+
+        let mut exclusive: HashMap<UWord, u64> = HashMap::new();
+        for node in &profile.nodes[1..] {
+            *exclusive.entry(node.entry).or_default() += node.count;
+        }
+        // Procedures still running when the program stopped
+        let mut rows: Vec<(UWord, u64, u64, u64)> = (profile.procedures.iter())
+            .map(|(&entry, p)| {
+                let running = if p.depth > 0 { total - p.start } else { 0 };
+                let excl = exclusive.get(&entry).copied().unwrap_or(0);
+                (entry, p.calls, p.inclusive + running, excl)
+            })
+            .collect();
+        rows.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+
+        writeln!(out, "PROFILE  {} INSTRUCTIONS", total)?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "PROCEDURE            CALLS    INCLUSIVE       %    EXCLUSIVE       %"
+        )?;
+        writeln!(
+            out,
+            "{:<16} {:>9} {:>12} {:>6.1}% {:>12} {:>6.1}%",
+            names[&0],
+            0,
+            total,
+            percent(total),
+            profile.nodes[0].count,
+            percent(profile.nodes[0].count)
+        )?;
+        for (entry, calls, incl, excl) in rows {
+            writeln!(
+                out,
+                "{:<16} {:>9} {:>12} {:>6.1}% {:>12} {:>6.1}%",
+                names[&entry],
+                calls,
+                incl,
+                percent(incl),
+                excl,
+                percent(excl)
+            )?;
+        }
+
+        let mut hot: Vec<(usize, u64)> = (profile.hits.iter().enumerate())
+            .filter(|&(_, &n)| n > 0)
+            .map(|(addr, &n)| (addr, n))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        writeln!(out)?;
+        writeln!(out, "HOT SPOTS")?;
+        writeln!(out, " ADDR        COUNT       %  INSTRUCTION")?;
+        for (addr, n) in hot.into_iter().take(HOT_SPOTS) {
+            let ins = self.instruction_at(addr).map_or("?".to_string(), |i| i.to_string());
+            writeln!(out, "{:5} {:12} {:6.1}%  {}", addr, n, percent(n), ins)?;
+        }
+        out.flush()
+    }
+
+    /// Writes the exclusive counts as collapsed stacks, one line per call
+    /// path (`STARTUP;G1@406;G97@520 1234`), as flame graph tools read.
+    pub fn write_profile_stacks(&self, mut out: impl Write) -> io::Result<()> {
+        let Some(profile) = &self.profile else {
+            return Ok(());
+        };
+        let names = self.procedure_names();
+        let mut lines: Vec<String> = (profile.nodes.iter().enumerate())
+            .filter(|(_, node)| node.count > 0)
+            .map(|(i, node)| {
+                let mut line = names[&0].clone();
+                for entry in profile.path(i) {
+                    line.push(';');
+                    line.push_str(&names[&entry]);
+                }
+                format!("{} {}", line, node.count)
+            })
+            .collect();
+        lines.sort();
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        out.flush()
+    }
+}
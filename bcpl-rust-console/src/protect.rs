@@ -0,0 +1,86 @@
+// Write protection. Once code is loaded its instructions are only read, so
+// a program store into one is a bug that would otherwise surface much later
+// as a bad instruction. The data that D and C directives place among them
+// (STATIC variables, TABLEs and strings) stays writable. Stores between
+// PROGSTART and LOMEM go through a cold check; everything else costs two
+// comparisons.
+
+use crate::*;
+
+/// Which stores by the program are checked. The default checks none.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Protection {
+    /// Stores into instructions assembled between PROGSTART and LOMEM fail
+    /// with `VmError::WriteProtected`.
+    pub code: bool,
+    /// The first store that changes a global set by a `G` directive, which
+    /// normally holds a procedure entry, is reported as a warning (see
+    /// `BcplState::set_warnings`).
+    pub globals: bool,
+}
+
+impl BcplState {
+    /// Sets which stores are checked. Code loaded later is protected as
+    /// well once it has been assembled.
+    pub fn set_protection(&mut self, protection: Protection) {
+        self.protection = protection;
+        self.guard();
+    }
+
+    pub fn protection(&self) -> Protection {
+        self.protection
+    }
+
+    // Recomputes the addresses whose stores need a closer look.
+    pub(crate) fn guard(&mut self) {
+        let Protection { code, globals } = self.protection;
+        let start = if globals { 0 } else { self.progstart };
+        let end = if code { self.lomem } else { self.progstart };
+        self.guarded = if code || globals { start..end } else { 0..0 };
+    }
+
+    // Marks the words assembled from `start` up to LOMEM as instructions.
+    pub(crate) fn mark_code(&mut self, start: usize) {
+        if self.code_words.len() < self.lomem {
+            self.code_words.resize(self.lomem, false);
+        }
+        self.code_words[start..self.lomem].fill(true);
+    }
+
+    // Whether a store to `addr` needs checking, in one comparison.
+    #[inline(always)]
+    pub(crate) fn guards(&self, addr: usize) -> bool {
+        addr.wrapping_sub(self.guarded.start) < self.guarded.end - self.guarded.start
+    }
+
+    // Checks a store into the guarded range made by the instruction at
+    // `pc`, with `sp` the stack pointer at the time.
+    #[cold]
+    pub(crate) fn guarded_store(
+        &mut self,
+        addr: usize,
+        val: Word,
+        (pc, sp): (UWord, UWord),
+    ) -> Result<(), VmError> {
+        if addr >= self.progstart {
+            if self.protection.code && self.code_words.get(addr) == Some(&true) {
+                return Err(VmError::WriteProtected { addr, at: Context::default() });
+            }
+            return Ok(());
+        }
+        let old = self.m[addr];
+        if self.protection.globals && self.entry_globals[addr] && old != val {
+            let text = format!(
+                "WARNING STORE OF {} TO {} OVERWRITES ENTRY {} AT PC {} SP {}",
+                val,
+                self.global_label(addr),
+                old,
+                pc,
+                sp
+            );
+            self.warn(&text);
+            self.entry_globals[addr] = false;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+// Snapshots of a running machine. Where an image holds a program ready to
+// start, a snapshot holds everything needed to carry on from where it was
+// taken: the whole store, the registers, the heap and the open streams. All
+// fields are little-endian:
+//
+//   magic "ICINTSNP", version (2 bytes), bytes per word (2 bytes), 1 if the
+//   heap follows (2 bytes),
+//   wordcount, progstart, lomem, himem, PC and SP (4 bytes each), A and B,
+//   CIS, COS, SYSIN and SYSPRINT (4 bytes each), CH,
+//   every word of the store,
+//   bits for the instruction words up to lomem, the globals still holding
+//   their G directive entries and the globals assigned, as in an image,
+//   the number of stream slots (4 bytes) and for each a kind byte: 0 for an
+//   empty slot, 1 for a stream the host supplied, 2 for a file being read
+//   and 3 for one being written, the last two followed by the offset
+//   (8 bytes) and the path as a length (4 bytes) and its bytes,
+//   then, with coroutines, the heap top, the free list and the allocated
+//   blocks, each list as a count and (address, size) pairs (4 bytes each).
+//
+// Files are reopened by path at their offsets; a file being written is cut
+// back to what had been written when the snapshot was taken. Streams the
+// host supplied, such as the terminal, are not saved: the machine the
+// snapshot is restored into keeps its own in those slots.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::image::{read_bits, read_u16, read_u32, read_word, write_bits};
+use crate::stream::{FileInput, FileOutput, FilePosition};
+use crate::*;
+
+const MAGIC: &[u8; 8] = b"ICINTSNP";
+/// Version of the snapshot format written by this build.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+// Longest file name a stream slot may hold.
+const MAX_PATH: usize = 4096;
+
+const EMPTY: u8 = 0;
+const HOST: u8 = 1;
+const INPUT: u8 = 2;
+const OUTPUT: u8 = 3;
+
+fn truncated(_: io::Error) -> VmError {
+    VmError::BadSnapshot("TRUNCATED".to_string())
+}
+
+fn write_u32(out: &mut impl Write, n: usize) -> io::Result<()> {
+    out.write_all(&(n as u32).to_le_bytes())
+}
+
+#[cfg(feature = "coroutines")]
+fn write_pairs(out: &mut impl Write, pairs: &[(usize, usize)]) -> io::Result<()> {
+    write_u32(out, pairs.len())?;
+    for &(addr, size) in pairs {
+        write_u32(out, addr)?;
+        write_u32(out, size)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "coroutines")]
+fn read_pairs(input: &mut impl Read) -> io::Result<Vec<(usize, usize)>> {
+    (0..read_u32(input)?)
+        .map(|_| Ok((read_u32(input)?, read_u32(input)?)))
+        .collect()
+}
+
+// What a stream slot holds, as saved.
+enum Slot {
+    Empty,
+    Host,
+    File(FilePosition),
+}
+
+fn read_slot(input: &mut impl Read) -> Result<Slot, VmError> {
+    let mut kind = [0u8];
+    input.read_exact(&mut kind).map_err(truncated)?;
+    match kind[0] {
+        EMPTY => Ok(Slot::Empty),
+        HOST => Ok(Slot::Host),
+        INPUT | OUTPUT => {
+            let mut offset = [0u8; 8];
+            input.read_exact(&mut offset).map_err(truncated)?;
+            let len = read_u32(input).map_err(truncated)?;
+            if len > MAX_PATH {
+                return Err(VmError::BadSnapshot(format!("FILE NAME OF {} BYTES", len)));
+            }
+            let mut path = vec![0u8; len];
+            input.read_exact(&mut path).map_err(truncated)?;
+            let path = String::from_utf8(path)
+                .map_err(|_| VmError::BadSnapshot("BAD FILE NAME".to_string()))?;
+            Ok(Slot::File(FilePosition {
+                path,
+                output: kind[0] == OUTPUT,
+                offset: u64::from_le_bytes(offset),
+            }))
+        }
+        kind => Err(VmError::BadSnapshot(format!("BAD STREAM KIND {}", kind))),
+    }
+}
+
+fn cannot_reopen(pos: &FilePosition) -> VmError {
+    VmError::BadSnapshot(format!("CANNOT REOPEN {}", pos.path))
+}
+
+// Opens a saved file stream's file again, which must still hold what had
+// been read or written when the snapshot was taken.
+fn reopen(pos: &FilePosition) -> Result<File, VmError> {
+    let file = if pos.output {
+        OpenOptions::new().write(true).open(&pos.path)
+    } else {
+        File::open(&pos.path)
+    }
+    .map_err(|_| cannot_reopen(pos))?;
+    if file.metadata().map_err(|_| cannot_reopen(pos))?.len() < pos.offset {
+        return Err(VmError::BadSnapshot(format!("{} IS SHORTER THAN WHEN SAVED", pos.path)));
+    }
+    Ok(file)
+}
+
+// Makes a reopened file a stream at its saved offset. A file being written
+// is cut back to that offset, dropping anything written after the snapshot
+// was taken, so the restored run writes it as the first run did.
+fn resume_stream(mut file: File, pos: &FilePosition) -> Result<Box<dyn Stream>, VmError> {
+    let cannot = || cannot_reopen(pos);
+    if pos.output {
+        file.set_len(pos.offset).map_err(|_| cannot())?;
+    }
+    file.seek(SeekFrom::Start(pos.offset)).map_err(|_| cannot())?;
+    Ok(if pos.output {
+        Box::new(FileOutput::new(&pos.path, file, pos.offset))
+    } else {
+        Box::new(FileInput::new(&pos.path, file, pos.offset))
+    })
+}
+
+impl BcplState {
+    /// Writes a snapshot that resumes with the current registers. Streams
+    /// are flushed first so the saved offsets match the files.
+    pub fn write_snapshot(&mut self, mut out: impl Write) -> io::Result<()> {
+        self.flush();
+        let regs = self.regs;
+        out.write_all(MAGIC)?;
+        out.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        out.write_all(&(BYTESPERWORD as u16).to_le_bytes())?;
+        out.write_all(&u16::from(cfg!(feature = "coroutines")).to_le_bytes())?;
+        for n in [self.m.len(), self.progstart, self.lomem, self.himem] {
+            write_u32(&mut out, n)?;
+        }
+        write_u32(&mut out, regs.pc as usize)?;
+        write_u32(&mut out, regs.sp as usize)?;
+        out.write_all(&regs.a.to_le_bytes())?;
+        out.write_all(&regs.b.to_le_bytes())?;
+        for n in [self.cis, self.cos, self.sysin, self.sysprint] {
+            write_u32(&mut out, n)?;
+        }
+        out.write_all(&self.ch.to_le_bytes())?;
+        for w in &self.m {
+            out.write_all(&w.to_le_bytes())?;
+        }
+        write_bits(&mut out, (0..self.lomem).map(|a| self.code_words.get(a) == Some(&true)))?;
+        write_bits(&mut out, self.entry_globals.iter().copied())?;
+        write_bits(&mut out, self.assigned.iter().copied())?;
+
+        write_u32(&mut out, self.files.len())?;
+        for slot in &self.files {
+            let Some(stream) = slot else {
+                out.write_all(&[EMPTY])?;
+                continue;
+            };
+            let Some(pos) = stream.file_position() else {
+                out.write_all(&[HOST])?;
+                continue;
+            };
+            out.write_all(&[if pos.output { OUTPUT } else { INPUT }])?;
+            out.write_all(&pos.offset.to_le_bytes())?;
+            write_u32(&mut out, pos.path.len())?;
+            out.write_all(pos.path.as_bytes())?;
+        }
+
+        #[cfg(feature = "coroutines")]
+        {
+            let allocated: Vec<(usize, usize)> =
+                self.heap.alloc_sizes.iter().map(|(&addr, &size)| (addr, size)).collect();
+            write_u32(&mut out, self.heap.top)?;
+            write_pairs(&mut out, &self.heap.free_list)?;
+            write_pairs(&mut out, &allocated)?;
+        }
+        out.flush()
+    }
+
+    /// Restores a snapshot written by `write_snapshot`, replacing the store,
+    /// registers, heap and file streams; `run` then carries on from there.
+    /// The machine must have the same geometry and word size, and the same
+    /// coroutine support, as the one that took the snapshot.
+    pub fn read_snapshot(&mut self, mut input: impl Read) -> Result<(), VmError> {
+        let bad = |msg: String| VmError::BadSnapshot(msg);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != MAGIC {
+            return Err(bad("NOT AN ICINT SNAPSHOT".to_string()));
+        }
+        let version = read_u16(&mut input).map_err(truncated)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(bad(format!("VERSION {} NOT SUPPORTED", version)));
+        }
+        let word_bytes = read_u16(&mut input).map_err(truncated)? as usize;
+        if word_bytes != BYTESPERWORD {
+            return Err(bad(format!("{}-BYTE WORDS", word_bytes)));
+        }
+        let has_heap = read_u16(&mut input).map_err(truncated)? != 0;
+        if has_heap != cfg!(feature = "coroutines") {
+            let with = if has_heap { "WITH" } else { "WITHOUT" };
+            return Err(bad(format!("TAKEN {} COROUTINES", with)));
+        }
+        let mut geometry = [0; 4];
+        for n in &mut geometry {
+            *n = read_u32(&mut input).map_err(truncated)?;
+        }
+        let [wordcount, progstart, lomem, himem] = geometry;
+        if wordcount != self.m.len() || progstart != self.progstart {
+            return Err(bad(format!(
+                "{} WORDS FROM {} BUT MACHINE HAS {} FROM {}",
+                wordcount,
+                progstart,
+                self.m.len(),
+                self.progstart
+            )));
+        }
+        if lomem < progstart || lomem > himem || himem >= wordcount {
+            return Err(bad(format!("LOMEM {} AND HIMEM {} DO NOT FIT", lomem, himem)));
+        }
+
+        // Read everything before touching the machine, so a bad snapshot
+        // leaves it as it was.
+        let regs = Regs {
+            pc: read_u32(&mut input).map_err(truncated)? as UWord,
+            sp: read_u32(&mut input).map_err(truncated)? as UWord,
+            a: read_word(&mut input).map_err(truncated)?,
+            b: read_word(&mut input).map_err(truncated)?,
+        };
+        let mut streams = [0; 4];
+        for n in &mut streams {
+            *n = read_u32(&mut input).map_err(truncated)?;
+        }
+        let ch = read_word(&mut input).map_err(truncated)?;
+        let m = (0..wordcount)
+            .map(|_| read_word(&mut input))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(truncated)?;
+        let code_words = read_bits(&mut input, lomem).map_err(truncated)?;
+        let entry_globals = read_bits(&mut input, progstart).map_err(truncated)?;
+        let assigned = read_bits(&mut input, progstart).map_err(truncated)?;
+        let slots = (0..read_u32(&mut input).map_err(truncated)?)
+            .map(|_| read_slot(&mut input))
+            .collect::<Result<Vec<_>, _>>()?;
+        #[cfg(feature = "coroutines")]
+        let heap = {
+            let top = read_u32(&mut input).map_err(truncated)?;
+            if top >= wordcount {
+                return Err(bad(format!("HEAP TOP {}", top)));
+            }
+            // Blocks lie between the code and the top of the store.
+            let check = |pairs: &[(usize, usize)]| {
+                for &(addr, size) in pairs {
+                    if addr < lomem || addr.checked_add(size).is_none_or(|end| end > wordcount) {
+                        return Err(bad(format!("HEAP BLOCK {} OF {} WORDS", addr, size)));
+                    }
+                }
+                Ok(())
+            };
+            let free_list = read_pairs(&mut input).map_err(truncated)?;
+            check(&free_list)?;
+            let allocated = read_pairs(&mut input).map_err(truncated)?;
+            check(&allocated)?;
+            let alloc_sizes = allocated.into_iter().collect();
+            Heap { wordcount, top, free_list, alloc_sizes }
+        };
+
+        // Every file is opened before any is cut back, so a file that has
+        // gone leaves the others as they were.
+        let opened = (slots.iter())
+            .map(|slot| match slot {
+                Slot::File(pos) => reopen(pos).map(|file| Some((file, pos))),
+                Slot::Empty | Slot::Host => Ok(None),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut files = (opened.into_iter())
+            .map(|opened| opened.map(|(file, pos)| resume_stream(file, pos)).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.flush();
+        for (i, slot) in slots.iter().enumerate() {
+            if let Slot::Host = slot {
+                files[i] = self.files.get_mut(i).and_then(Option::take);
+            }
+        }
+        self.files = files;
+        [self.cis, self.cos, self.sysin, self.sysprint] = streams;
+        self.ch = ch;
+        self.m = m;
+        self.lomem = lomem;
+        self.himem = himem;
+        self.code_words = code_words;
+        self.entry_globals = entry_globals;
+        self.assigned = assigned;
+        #[cfg(feature = "coroutines")]
+        {
+            self.heap = heap;
+        }
+        // Labels belong to whatever was assembled here, not to the snapshot.
+        self.section_labels.clear();
+        self.regs = regs;
+        self.usage = Usage::default();
+        self.guard();
+        Ok(())
+    }
+
+    /// Writes a snapshot of the machine to `filename`.
+    pub fn save_snapshot(&mut self, filename: &str) -> Result<(), VmError> {
+        let file = File::create(filename).map_err(|_| VmError::NoOutput(filename.to_string()))?;
+        self.write_snapshot(BufWriter::new(file))
+            .map_err(|_| VmError::NoOutput(filename.to_string()))
+    }
+
+    /// Restores the snapshot file `filename`.
+    pub fn load_snapshot(&mut self, filename: &str) -> Result<(), VmError> {
+        let file = File::open(filename).map_err(|_| VmError::NoInput(filename.to_string()))?;
+        self.read_snapshot(BufReader::new(file)).map_err(|e| match e {
+            VmError::BadSnapshot(msg) => VmError::BadSnapshot(format!("{} {}", filename, msg)),
+            e => e,
+        })
+    }
+}
@@ -0,0 +1,174 @@
+// Byte streams behind the BCPL stream table. SYSIN/SYSPRINT, files opened by
+// FINDINPUT/FINDOUTPUT and host-provided devices all implement `Stream`.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::rc::Rc;
+
+/// A BCPL input or output stream.
+///
+/// Input streams implement `read_byte`, output streams `write_bytes`; the
+/// defaults make a stream that is empty and discards output.
+pub trait Stream {
+    /// Next byte, or `None` at end of stream.
+    fn read_byte(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn write_bytes(&mut self, _buf: &[u8]) {}
+
+    /// Called by `NEWLINE` and for `*N` after the newline is written.
+    fn end_line(&mut self) {}
+
+    fn flush(&mut self) {}
+
+    /// The file and offset the stream has reached, for streams a snapshot
+    /// can reopen. Other streams are left to the host.
+    fn file_position(&self) -> Option<FilePosition> {
+        None
+    }
+}
+
+/// Where a file stream is: its path, whether it is being written and how
+/// many bytes have been read or written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilePosition {
+    pub path: String,
+    pub output: bool,
+    pub offset: u64,
+}
+
+/// A file opened by FINDINPUT or `-i`, which remembers where it has read to.
+pub struct FileInput {
+    path: String,
+    offset: u64,
+    reader: BufReader<File>,
+}
+
+impl FileInput {
+    /// `file` must be open at `offset` bytes from the start of `path`.
+    pub fn new(path: &str, file: File, offset: u64) -> Self {
+        FileInput { path: path.to_string(), offset, reader: BufReader::new(file) }
+    }
+}
+
+impl Stream for FileInput {
+    fn read_byte(&mut self) -> Option<u8> {
+        let c = ReadStream(&mut self.reader).read_byte()?;
+        self.offset += 1;
+        Some(c)
+    }
+
+    fn file_position(&self) -> Option<FilePosition> {
+        Some(FilePosition { path: self.path.clone(), output: false, offset: self.offset })
+    }
+}
+
+/// A file opened by FINDOUTPUT or `-o`, which remembers how much has been
+/// written.
+pub struct FileOutput {
+    path: String,
+    offset: u64,
+    writer: BufWriter<File>,
+}
+
+impl FileOutput {
+    /// `file` must be open at `offset` bytes from the start of `path`.
+    pub fn new(path: &str, file: File, offset: u64) -> Self {
+        FileOutput { path: path.to_string(), offset, writer: BufWriter::new(file) }
+    }
+}
+
+impl Stream for FileOutput {
+    fn write_bytes(&mut self, buf: &[u8]) {
+        if self.writer.write_all(buf).is_ok() {
+            self.offset += buf.len() as u64;
+        }
+    }
+
+    fn flush(&mut self) {
+        let _ = self.writer.flush();
+    }
+
+    fn file_position(&self) -> Option<FilePosition> {
+        Some(FilePosition { path: self.path.clone(), output: true, offset: self.offset })
+    }
+}
+
+/// Input stream over any `Read`.
+pub struct ReadStream<R: Read>(pub R);
+
+impl<R: Read> Stream for ReadStream<R> {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut buf = [0u8; 1];
+        match self.0.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+}
+
+/// Output stream over any `Write`.
+pub struct WriteStream<W: Write>(pub W);
+
+impl<W: Write> Stream for WriteStream<W> {
+    fn write_bytes(&mut self, buf: &[u8]) {
+        let _ = self.0.write_all(buf);
+    }
+
+    fn flush(&mut self) {
+        let _ = self.0.flush();
+    }
+}
+
+/// The process standard input.
+pub struct Stdin;
+
+impl Stream for Stdin {
+    fn read_byte(&mut self) -> Option<u8> {
+        ReadStream(io::stdin()).read_byte()
+    }
+}
+
+/// The process standard output, flushed at the end of each line.
+pub struct Stdout;
+
+impl Stream for Stdout {
+    fn write_bytes(&mut self, buf: &[u8]) {
+        let _ = io::stdout().write(buf);
+    }
+
+    fn end_line(&mut self) {
+        let _ = io::stdout().flush();
+    }
+
+    fn flush(&mut self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Output stream that collects everything written into a shared buffer, so
+/// the host can read a program's output after the run.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Stream for SharedBuffer {
+    fn write_bytes(&mut self, buf: &[u8]) {
+        self.0.borrow_mut().extend_from_slice(buf);
+    }
+}
@@ -0,0 +1,189 @@
+// Global names read from BCPL headers. Only `GLOBAL $( NAME:n ... $)`
+// blocks are looked at; the names are used to label globals, procedures
+// and K-codes in diagnostics, and for nothing else.
+
+use std::fs;
+
+use crate::*;
+
+// Skips spaces, newlines, separators and comments.
+fn skip_blanks(text: &[u8], mut i: usize) -> usize {
+    while i < text.len() {
+        if text[i..].starts_with(b"//") {
+            while i < text.len() && text[i] != b'\n' {
+                i += 1;
+            }
+        } else if text[i..].starts_with(b"/*") {
+            i = (i + 2..text.len())
+                .find(|&j| text[j..].starts_with(b"*/"))
+                .map_or(text.len(), |j| j + 2);
+        } else if text[i].is_ascii_whitespace() || text[i] == b';' || text[i] == b',' {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    i
+}
+
+fn word_at(text: &[u8], i: usize) -> &[u8] {
+    let end = (i..text.len())
+        .find(|&j| !(text[j].is_ascii_alphanumeric() || text[j] == b'.' || text[j] == b'_'))
+        .unwrap_or(text.len());
+    &text[i..end]
+}
+
+// A decimal, `#` octal or `#X` hexadecimal number.
+fn number(word: &[u8]) -> Option<usize> {
+    let word = std::str::from_utf8(word).ok()?;
+    match word.strip_prefix('#') {
+        Some(hex) if hex.starts_with(['X', 'x']) => usize::from_str_radix(&hex[1..], 16).ok(),
+        Some(octal) => usize::from_str_radix(octal, 8).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+// The (name, global) pairs declared in the GLOBAL blocks of `text`.
+fn parse_globals(text: &[u8]) -> Vec<(String, usize)> {
+    let mut globals = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        i = skip_blanks(text, i);
+        if i >= text.len() {
+            break;
+        }
+        let word = word_at(text, i);
+        if word.is_empty() {
+            // Strings may hold anything, including the word GLOBAL.
+            if text[i] == b'"' {
+                i += 1;
+                while i < text.len() && text[i] != b'"' {
+                    i += if text[i] == b'*' { 2 } else { 1 };
+                }
+            }
+            i += 1;
+            continue;
+        }
+        i += word.len();
+        if !word.eq_ignore_ascii_case(b"GLOBAL") {
+            continue;
+        }
+        i = skip_blanks(text, i);
+        if text[i..].starts_with(b"$(") {
+            i += 2 + word_at(text, i + 2).len();
+        } else if text[i..].starts_with(b"{") {
+            i += 1;
+        } else {
+            continue;
+        }
+        // NAME:n items up to the closing bracket
+        loop {
+            i = skip_blanks(text, i);
+            if i >= text.len() || text[i..].starts_with(b"$)") || text[i] == b'}' {
+                break;
+            }
+            let name = word_at(text, i);
+            if name.is_empty() {
+                i += 1;
+                continue;
+            }
+            i += name.len();
+            let colon = skip_blanks(text, i);
+            if text.get(colon) != Some(&b':') {
+                continue;
+            }
+            let start = skip_blanks(text, colon + 1);
+            let n = if text.get(start) == Some(&b'#') {
+                &text[start..start + 1 + word_at(text, start + 1).len()]
+            } else {
+                word_at(text, start)
+            };
+            i = start + n.len();
+            if let Some(n) = number(n) {
+                globals.push((String::from_utf8_lossy(name).into_owned(), n));
+            }
+        }
+    }
+    globals
+}
+
+impl BcplState {
+    /// Reads the global names declared in the BCPL header `filename`.
+    /// Returns how many were found.
+    pub fn load_symbols(&mut self, filename: &str) -> Result<usize, VmError> {
+        let text = fs::read(filename).map_err(|_| VmError::NoInput(filename.to_string()))?;
+        Ok(self.add_symbols(&String::from_utf8_lossy(&text)))
+    }
+
+    /// Takes global names from the `GLOBAL` declarations in `text`. A
+    /// global already named keeps its first name; globals at or above
+    /// PROGSTART are ignored. Returns how many names were found.
+    pub fn add_symbols(&mut self, text: &str) -> usize {
+        let globals = parse_globals(text.as_bytes());
+        for (name, g) in &globals {
+            if let Some(slot) = self.global_names.get_mut(*g)
+                && slot.is_none()
+            {
+                *slot = Some(name.clone());
+            }
+        }
+        globals.len()
+    }
+
+    /// The header name of global `g`, if one has been loaded.
+    pub fn global_name(&self, g: usize) -> Option<&str> {
+        self.global_names.get(g)?.as_deref()
+    }
+
+    /// The global with header name `name`, ignoring case.
+    pub fn global_named(&self, name: &str) -> Option<usize> {
+        (self.global_names.iter())
+            .position(|n| n.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+    }
+
+    // Name of what a call with A = `a` reaches: the K-code or the
+    // procedure held in a named global.
+    pub(crate) fn callee_name(&self, a: Word) -> Option<&str> {
+        let a = a as UWord as usize;
+        if a < self.progstart {
+            return self.global_name(a);
+        }
+        (0..self.progstart)
+            .find(|&g| self.m[g] as UWord as usize == a && self.global_name(g).is_some())
+            .and_then(|g| self.global_name(g))
+    }
+
+    // The named procedure `pc` is in, as NAME+OFFSET: the one with the
+    // highest entry at or below `pc`. Procedures not held in globals are
+    // taken for part of the one before them.
+    pub(crate) fn procedure_at(&self, pc: UWord) -> Option<String> {
+        let pc = pc as usize;
+        let (entry, g) = (0..self.progstart)
+            .filter(|&g| self.global_name(g).is_some())
+            .map(|g| (self.m[g] as UWord as usize, g))
+            .filter(|&(entry, _)| entry >= self.progstart && entry <= pc && pc < self.lomem)
+            .max()?;
+        let name = self.global_name(g)?;
+        Some(if pc == entry { name.to_string() } else { format!("{}+{}", name, pc - entry) })
+    }
+
+    // The global an instruction reads or writes directly, such as G76 in
+    // LIG76 or SG76, with its name.
+    pub(crate) fn operand_name(&self, ins: &Instruction) -> Option<&str> {
+        let direct = !ins.p && (ins.indirect || ins.op == F1_S);
+        let g = ins.d as UWord as usize;
+        if direct { self.global_name(g) } else { None }
+    }
+
+    // "G76 (WRITEF)", or "G76" with no name loaded.
+    pub(crate) fn global_label(&self, g: usize) -> String {
+        match self.global_name(g) {
+            Some(name) => format!("G{} ({})", g, name),
+            None => format!("G{}", g),
+        }
+    }
+
+    pub(crate) fn has_symbols(&self) -> bool {
+        self.global_names.iter().any(Option::is_some)
+    }
+}
@@ -0,0 +1,142 @@
+// Instruction trace. While a trace is set, `run` takes the slower observed
+// path that logs each executed instruction passing the filter; once the
+// instruction count window has passed it drops back to the normal loop.
+
+use std::ops::RangeInclusive;
+
+use crate::*;
+
+/// Which executed instructions a trace records. The default records all.
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    /// Only instructions at these addresses.
+    pub addrs: Option<RangeInclusive<usize>>,
+    /// Only K instructions (calls of procedures and K-codes).
+    pub calls: bool,
+    /// Only J, T and F instructions. With `calls`, both kinds are kept.
+    pub jumps: bool,
+    /// Only instructions whose number in the run, counting from 1, is in
+    /// this range.
+    pub count: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    fn matches(&self, count: u64, pc: usize, ins: &Instruction) -> bool {
+        if let Some(window) = &self.count
+            && !window.contains(&count)
+        {
+            return false;
+        }
+        if let Some(addrs) = &self.addrs
+            && !addrs.contains(&pc)
+        {
+            return false;
+        }
+        let call = ins.op == F6_K;
+        let jump = matches!(ins.op, F3_J | F4_T | F5_F);
+        match (self.calls, self.jumps) {
+            (false, false) => true,
+            (calls, jumps) => calls && call || jumps && jump,
+        }
+    }
+
+    // No instruction after number `count` can match.
+    fn finished(&self, count: u64) -> bool {
+        matches!(&self.count, Some(window) if count >= *window.end())
+    }
+}
+
+/// Where a trace goes and what it records.
+pub struct Trace {
+    out: Box<dyn Stream>,
+    filter: TraceFilter,
+    // Instructions executed since the trace was set
+    count: u64,
+}
+
+impl Trace {
+    pub fn new(out: Box<dyn Stream>, filter: TraceFilter) -> Self {
+        Trace { out, filter, count: 0 }
+    }
+}
+
+impl BcplState {
+    /// Logs executed instructions to `trace.out`, or stops tracing.
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    // Whether a trace is set that may still record something.
+    pub(crate) fn tracing(&self) -> bool {
+        (self.trace.as_ref()).is_some_and(|t| !t.filter.finished(t.count))
+    }
+
+    // `execute`, logging the instruction if the filter wants it.
+    pub(crate) fn execute_traced(&mut self, r: &mut Regs) -> Result<Option<Word>, VmError> {
+        let Some(trace) = &mut self.trace else {
+            return self.execute_any(r);
+        };
+        trace.count += 1;
+        let count = trace.count;
+        let before = *r;
+        let ins = self.instruction_at(r.pc as usize);
+        let wanted = ins.filter(|ins| self.trace_wanted(count, before.pc, ins));
+        let d = wanted.and_then(|ins| self.effective_d(&before, &ins));
+        // The global named by the operand, or the callee of a K
+        let name = wanted.and_then(|ins| match ins.op {
+            F6_K => self.callee_name(before.a),
+            _ => self.operand_name(&ins),
+        });
+        let text = wanted.map(|ins| match name {
+            Some(name) => format!("{} {}", ins, name),
+            None => ins.to_string(),
+        });
+
+        let result = self.execute_any(r);
+        if let Some(text) = text {
+            let after = match &result {
+                Ok(_) => format!("PC={} A={} B={} SP={}", r.pc, r.a, r.b, r.sp),
+                Err(e) => e.to_string(),
+            };
+            let d = d.map_or("?".to_string(), |d| d.to_string());
+            let line = format!(
+                "{:8} {:5}: {:<10} D={:<6} A={} B={} SP={} -> {}\n",
+                count,
+                before.pc,
+                text,
+                d,
+                before.a,
+                before.b,
+                before.sp,
+                after
+            );
+            if let Some(trace) = &mut self.trace {
+                trace.out.write_bytes(line.as_bytes());
+            }
+        }
+        result
+    }
+
+    fn trace_wanted(&self, count: u64, pc: UWord, ins: &Instruction) -> bool {
+        (self.trace.as_ref()).is_some_and(|t| t.filter.matches(count, pc as usize, ins))
+    }
+
+    // The operand as the instruction will use it, after adding SP and
+    // indirection.
+    fn effective_d(&self, r: &Regs, ins: &Instruction) -> Option<UWord> {
+        let mut d = ins.d as UWord;
+        if ins.p {
+            d = d.wrapping_add(r.sp);
+        }
+        if ins.indirect {
+            d = *self.m.get(d as usize)? as UWord;
+        }
+        Some(d)
+    }
+
+    pub(crate) fn flush_trace(&mut self) {
+        if let Some(trace) = &mut self.trace {
+            trace.out.flush();
+        }
+    }
+}
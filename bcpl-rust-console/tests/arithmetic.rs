@@ -0,0 +1,95 @@
+mod common;
+
+use std::sync::OnceLock;
+
+use icint::{BcplState, VmError, Word};
+
+// Sets R to X op Y for the operator OP, all globals set by the test.
+const APPLY: &str = "GET \"LIBHDR\"\n\
+                     GLOBAL $( OP: 150; X: 151; Y: 152; R: 153 $)\n\
+                     LET START() BE R := VALOF SWITCHON OP INTO\n\
+                     $( CASE '/': RESULTIS X / Y\n\
+                        CASE 'R': RESULTIS X REM Y\n\
+                        CASE '<': RESULTIS X << Y\n\
+                        CASE '>': RESULTIS X >> Y $)\n";
+
+const BITS: Word = Word::BITS as Word;
+
+// Runs APPLY on `x` and `y`, giving the machine and the result.
+fn apply(op: char, x: Word, y: Word, traps: bool) -> (BcplState, Result<Word, VmError>) {
+    static INTCODE: OnceLock<String> = OnceLock::new();
+    let (mut state, _) = common::load(INTCODE.get_or_init(|| common::compile(APPLY)));
+    state.set_arithmetic_traps(traps);
+    for (g, value) in [(150, op as Word), (151, x), (152, y)] {
+        state.set_global(g, value);
+    }
+    let result = state.interpret().map(|_| state.global(153));
+    (state, result)
+}
+
+fn result(op: char, x: Word, y: Word, traps: bool) -> Result<Word, VmError> {
+    apply(op, x, y, traps).1
+}
+
+// Checks that the run stopped at the X instruction `n` with `err`.
+fn trapped_at(op: char, x: Word, y: Word, n: Word) -> VmError {
+    let (state, result) = apply(op, x, y, true);
+    let err = result.unwrap_err();
+    let pc = err.context().unwrap().pc;
+    let ins = state.instruction_at(pc).unwrap();
+    assert_eq!((ins.letter(), ins.d), ('X', n), "{}", err);
+    assert_eq!(state.registers().pc as usize, pc);
+    assert!(err.to_string().ends_with(&format!("AT PC {} SP {}", pc, state.registers().sp)));
+    err
+}
+
+#[test]
+fn division_by_zero_gives_zero() {
+    assert_eq!(result('/', 7, 0, false), Ok(0));
+    assert_eq!(result('R', 7, 0, false), Ok(0));
+    assert_eq!(result('/', Word::MIN, 0, false), Ok(0));
+}
+
+#[test]
+fn division_by_zero_traps() {
+    let err = trapped_at('/', 7, 0, 6);
+    assert!(matches!(err, VmError::DivideByZero { .. }), "{:?}", err);
+    let err = trapped_at('R', 7, 0, 7);
+    assert!(matches!(err, VmError::DivideByZero { .. }), "{:?}", err);
+}
+
+// The one quotient that does not fit wraps, with traps or without.
+#[test]
+fn min_divided_by_minus_one() {
+    for traps in [false, true] {
+        assert_eq!(result('/', Word::MIN, -1, traps), Ok(Word::MIN));
+        assert_eq!(result('R', Word::MIN, -1, traps), Ok(0));
+    }
+}
+
+#[test]
+fn shifts_in_range() {
+    for traps in [false, true] {
+        assert_eq!(result('<', 1, BITS - 1, traps), Ok(Word::MIN));
+        assert_eq!(result('>', -1, BITS - 1, traps), Ok(1));
+        assert_eq!(result('<', 5, 0, traps), Ok(5));
+    }
+}
+
+#[test]
+fn shifts_out_of_range_give_zero() {
+    for count in [BITS, BITS + 1, Word::MAX, -1, Word::MIN] {
+        assert_eq!(result('<', -1, count, false), Ok(0), "{}", count);
+        assert_eq!(result('>', -1, count, false), Ok(0), "{}", count);
+    }
+}
+
+#[test]
+fn shifts_out_of_range_trap() {
+    for count in [BITS, -1] {
+        let err = trapped_at('<', -1, count, 16);
+        assert!(matches!(err, VmError::BadShift { count: c, .. } if c == count), "{:?}", err);
+        let err = trapped_at('>', -1, count, 17);
+        assert!(matches!(err, VmError::BadShift { count: c, .. } if c == count), "{:?}", err);
+    }
+}
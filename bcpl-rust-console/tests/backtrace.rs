@@ -0,0 +1,56 @@
+mod common;
+
+use icint::{Limit, Limits, VmError};
+
+// F prints the backtrace from inside two calls.
+const NESTED: &str = "GET \"LIBHDR\"\n\
+                      GLOBAL $( F: 150; G: 151 $)\n\
+                      LET F(A, B) BE BACKTRACE()\n\
+                      LET G(X) BE F(X, 22)\n\
+                      LET START() BE G(11)\n";
+
+#[test]
+fn backtrace_kcode_output() {
+    let (mut state, out) = common::load(&common::compile(NESTED));
+    assert_eq!(state.interpret(), Ok(0));
+    state.flush();
+    let out = out.to_string_lossy();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[..2], ["BACKTRACE", "    #  FRAME     PC  WORDS"], "{}", out);
+    // F, G and START, then the start-up code
+    let frames: Vec<Vec<&str>> = lines[2..].iter().map(|l| l.split_whitespace().collect()).collect();
+    assert_eq!(frames.len(), 4, "{}", out);
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(frame[0], i.to_string(), "{}", out);
+        assert_eq!(frame.len(), 6, "{}", out);
+    }
+    assert_eq!(frames[0][3..5], ["11", "22"], "{}", out);
+    assert_eq!(frames[1][3], "11", "{}", out);
+    assert!(out.ends_with('\n'));
+}
+
+#[test]
+fn backtrace_names_procedures() {
+    let (mut state, out) = common::load(&common::compile(NESTED));
+    state.add_symbols("GLOBAL $( START: 1; F: 150; G: 151 $)");
+    assert_eq!(state.interpret(), Ok(0));
+    state.flush();
+    let out = out.to_string_lossy();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[1], "    #  FRAME     PC  PROCEDURE         WORDS");
+    let names: Vec<&str> = lines[2..].iter().map(|l| l.split_whitespace().nth(3).unwrap()).collect();
+    assert!(names[0].starts_with("F+"), "{}", out);
+    assert!(names[1].starts_with("G+"), "{}", out);
+    assert!(names[2].starts_with("START+"), "{}", out);
+}
+
+// The backtrace is output like any other.
+#[test]
+fn backtrace_counts_as_output() {
+    let (mut state, out) = common::load(&common::compile(NESTED));
+    state.set_limits(Limits { output: Some(20), ..Limits::default() });
+    let result = state.interpret();
+    assert!(matches!(result, Err(VmError::LimitExceeded { limit: Limit::Output(20), .. })));
+    state.flush();
+    assert_eq!(out.to_string_lossy(), "BACKTRACE\n    #  FRA");
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use icint::{Access, VmError};
+
+// Packs and unpacks bytes of V, then reads the byte at OFFSET.
+const BYTES: &str = "GET \"LIBHDR\"\n\
+                     GLOBAL $( OFFSET: 150 $)\n\
+                     LET START() BE\n\
+                     $( LET V = VEC 3\n\
+                     FOR I = 0 TO 5 DO PUTBYTE(V, I, 'A' + I)\n\
+                     FOR I = 5 TO 0 BY -1 DO WRCH(GETBYTE(V, I))\n\
+                     GETBYTE(V, OFFSET)\n\
+                     $)\n";
+
+#[test]
+fn bytes() {
+    let (mut state, out) = common::load(&common::compile(BYTES));
+    state.set_global(150, 0);
+    assert_eq!(state.interpret(), Ok(0));
+    state.flush();
+    assert_eq!(out.to_string_lossy(), "FEDCBA");
+}
+
+// An offset that takes the byte index past the largest is charged to the
+// vector.
+#[test]
+fn negative_offset() {
+    let (mut state, _) = common::load(&common::compile(BYTES));
+    state.set_global(150, -1);
+    let result = state.interpret();
+    let Err(VmError::BadAccess { access: Access::Load, addr, at }) = result else {
+        panic!("{:?}", result);
+    };
+    assert_eq!(state.instruction_at(at.pc).unwrap().letter(), 'K');
+    assert!(addr > state.progstart() && addr < state.memory().len(), "{}", addr);
+}
@@ -0,0 +1,90 @@
+// Helpers shared by the integration tests: the shipped compiler run in
+// memory, so tests can build their programs from BCPL source.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use icint::stream::{ReadStream, SharedBuffer};
+use icint::{BcplState, VmError, Word};
+
+/// The contents of `name` in the crate directory.
+pub fn shipped(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(name);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
+
+/// The header for this build's word size.
+pub fn libhdr() -> String {
+    shipped(if cfg!(feature = "word32") { "libhdr32" } else { "libhdr" })
+}
+
+pub fn input(text: &str) -> Box<ReadStream<Cursor<Vec<u8>>>> {
+    Box::new(ReadStream(Cursor::new(text.as_bytes().to_vec())))
+}
+
+/// Compiles BCPL `source` to INTCODE with syni, trni and cgi, as
+/// compile.sh does.
+pub fn compile(source: &str) -> String {
+    compile_with(source, &[("LIBHDR", &libhdr())])
+}
+
+/// As `compile`, with `headers` as the files that GET can read.
+pub fn compile_with(source: &str, headers: &[(&str, &str)]) -> String {
+    let ocode = SharedBuffer::new();
+    let mut state = BcplState::new();
+    state.load_str(&shipped("syni")).unwrap();
+    state.load_str(&shipped("trni")).unwrap();
+    state.set_sysin(input(source));
+    state.set_sysprint(Box::new(SharedBuffer::new()));
+    for (name, text) in headers {
+        state.attach_device(name, input(text));
+    }
+    state.attach_device("OCODE", Box::new(ocode.clone()));
+    assert_eq!(state.interpret(), Ok(0), "syni/trni failed");
+    state.flush();
+
+    let intcode = SharedBuffer::new();
+    let mut state = BcplState::new();
+    state.load_str(&shipped("cgi")).unwrap();
+    state.set_sysin(Box::new(ReadStream(Cursor::new(ocode.contents()))));
+    state.set_sysprint(Box::new(SharedBuffer::new()));
+    state.attach_device("INTCODE", Box::new(intcode.clone()));
+    assert_eq!(state.interpret(), Ok(0), "cgi failed");
+    state.flush();
+    intcode.to_string_lossy()
+}
+
+/// A machine with `intcode` loaded and its output going to the buffer
+/// returned.
+pub fn load(intcode: &str) -> (BcplState, SharedBuffer) {
+    let out = SharedBuffer::new();
+    let mut state = BcplState::new();
+    state.load_str(intcode).unwrap();
+    state.set_sysprint(Box::new(out.clone()));
+    (state, out)
+}
+
+/// Runs `intcode` to the end, giving its result and output.
+pub fn run(intcode: &str) -> (Result<Word, VmError>, String) {
+    let (mut state, out) = load(intcode);
+    let result = state.interpret();
+    state.flush();
+    (result, out.to_string_lossy())
+}
+
+/// Runs the icint command on `intcode`, written to a file of its own, with
+/// `args` before the file name.
+pub fn icint(intcode: &str, args: &[&str]) -> Output {
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+    let n = FILES.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("icint-test-{}-{}.int", std::process::id(), n));
+    fs::write(&path, intcode).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_icint")).args(args).arg(&path).output().unwrap();
+    let _ = fs::remove_file(&path);
+    output
+}
@@ -0,0 +1,51 @@
+#![cfg(feature = "coroutines")]
+
+mod common;
+
+// Frees a block twice and a word that was never handed out, then takes the
+// block again, which comes back from the free list.
+const FREEING: &str = "GET \"LIBHDR\"\n\
+                       LET START() BE\n\
+                       $( LET V = GETVEC(5)\n\
+                       WRITEN(FREEVEC(V)); WRITEN(FREEVEC(V)); WRITEN(FREEVEC(V + 1))\n\
+                       WRITEN(GETVEC(5) = V)\n\
+                       $)\n";
+
+// The coroutine header for this build's word size.
+fn libhdr() -> String {
+    let name = if cfg!(feature = "word32") { "libhdr32" } else { "libhdr" };
+    common::shipped(&format!("bcpl-with-coroutines/{}", name))
+}
+
+// Compiles `source` with the headers in bcpl-with-coroutines.
+fn compile(source: &str) -> String {
+    let coroutines = common::shipped("bcpl-with-coroutines/coroutines");
+    common::compile_with(source, &[("LIBHDR", &libhdr()), ("coroutines", &coroutines)])
+}
+
+// Compiles `name` from bcpl-with-coroutines.
+fn compile_shipped(name: &str) -> String {
+    compile(&common::shipped(&format!("bcpl-with-coroutines/{}", name)))
+}
+
+#[test]
+fn coroutines_switch() {
+    let (result, out) = common::run(&compile_shipped("test_coroutines_min.b"));
+    assert_eq!(result, Ok(0));
+    assert_eq!(out, "Coroutines work\n".repeat(5) + "Lines: 5\n");
+}
+
+#[test]
+fn getvec() {
+    let (result, out) = common::run(&compile_shipped("test_getvec.b"));
+    assert_eq!(result, Ok(0));
+    let values: String = (0..5).map(|i| format!("V!{} = {}\n", i, (i + 1) * 10)).collect();
+    assert_eq!(out, values + "TEST PASSED\n");
+}
+
+#[test]
+fn freevec() {
+    let (result, out) = common::run(&compile(FREEING));
+    assert_eq!(result, Ok(0));
+    assert_eq!(out, "100-1");
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use icint::{BcplState, Debugger};
+
+// Runs the debugger over `fact.b` with `script` as its commands, giving
+// the stop code and what the debugger wrote.
+fn debug(script: &str) -> (Option<i64>, String) {
+    let (mut state, _) = common::load(&common::compile(&common::shipped("fact.b")));
+    let mut out = Vec::new();
+    let result = Debugger::new().run(&mut state, script.as_bytes(), &mut out).unwrap();
+    (result.map(i64::from), String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn globals_with_large_count() {
+    let (result, out) = debug("g 1 18446744073709551615\nq\n");
+    assert_eq!(result, None);
+    let last = BcplState::new().progstart() - 1;
+    assert!(out.contains("* G1 = "), "{}", out);
+    assert!(out.contains(&format!("\nG{} = ", last)), "{}", out);
+}
+
+#[test]
+fn memory_with_large_count() {
+    let (result, out) = debug("x 0 18446744073709551615\nx 18446744073709551615 8\nc\n");
+    assert_eq!(result, Some(0));
+    let words = BcplState::new().memory().len();
+    assert!(out.contains(&format!("\n{:5}:", (words - 1) / 8 * 8)), "{}", out);
+    assert!(out.contains("* BAD ADDRESS 18446744073709551615\n"), "{}", out);
+    assert!(out.ends_with("STOPPED WITH 0\n* "), "{}", out);
+}
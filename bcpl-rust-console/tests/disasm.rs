@@ -0,0 +1,33 @@
+mod common;
+
+use icint::BcplState;
+
+// Disassembling the loaded code and assembling the listing gives the same
+// memory back, globals included.
+fn round_trip(sources: &[String]) {
+    let mut state = BcplState::new();
+    for source in sources {
+        state.load_str(source).unwrap();
+    }
+    let listing = state.disassemble();
+    let mut again = BcplState::new();
+    again.load_str(&listing).unwrap_or_else(|e| panic!("{}\n{}", e, listing));
+    assert_eq!(again.lomem(), state.lomem());
+    let differs = (0..state.lomem()).find(|&a| again.memory()[a] != state.memory()[a]);
+    assert_eq!(differs, None, "word differs");
+}
+
+#[test]
+fn synitrni() {
+    round_trip(&[common::shipped("syni"), common::shipped("trni")]);
+}
+
+#[test]
+fn cgi() {
+    round_trip(&[common::shipped("cgi")]);
+}
+
+#[test]
+fn compiled_program() {
+    round_trip(&[common::compile(&common::shipped("cmpltest.b"))]);
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use icint::{MAX_FLIGHT_RECORD, VmError};
+
+// Calls K-code 150, which nothing defines, after a loop long enough to
+// fill a small ring.
+const FAULT: &str = "GET \"LIBHDR\"\n\
+                     GLOBAL $( MISSING: 150 $)\n\
+                     LET START() BE $( FOR I = 1 TO 100 DO LOOP; MISSING() $)\n";
+
+#[test]
+fn recorder_keeps_the_last_instructions() {
+    let (mut state, _) = common::load(&common::compile(FAULT));
+    assert!(state.interpret().is_err());
+    assert!(state.flight_record().is_empty());
+
+    state.set_flight_recorder(50);
+    state.reset_registers();
+    let Err(VmError::UndefinedGlobal { at, .. }) = state.interpret() else {
+        panic!("no fault");
+    };
+    let record = state.flight_record();
+    assert_eq!(record.len(), 50);
+    let last = record.last().unwrap();
+    assert_eq!((last.pc as usize, last.instruction().letter()), (at.pc, 'K'));
+}
+
+#[test]
+fn recorder_size_is_capped() {
+    let (mut state, _) = common::load(&common::compile(FAULT));
+    state.set_flight_recorder(usize::MAX);
+    assert!(state.interpret().is_err());
+    let n = state.flight_record().len();
+    assert!(n > 50 && n < MAX_FLIGHT_RECORD, "{}", n);
+
+    state.set_flight_recorder(0);
+    state.reset_registers();
+    assert!(state.interpret().is_err());
+    assert!(state.flight_record().is_empty());
+}
+
+// The record goes to stderr, after the fault and backtrace on SYSPRINT.
+#[test]
+fn record_is_written_to_stderr() {
+    let output = common::icint(&common::compile(FAULT), &["--flight-recorder", "5"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stdout.contains("CALL TO UNDEFINED GLOBAL"), "{}", stdout);
+    assert!(!stdout.contains("INSTRUCTIONS"), "{}", stdout);
+    assert!(stderr.starts_with("LAST 5 INSTRUCTIONS\n"), "{}", stderr);
+}
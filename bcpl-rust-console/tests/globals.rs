@@ -0,0 +1,38 @@
+mod common;
+
+use icint::VmError;
+
+const MISSING: &str = "GET \"LIBHDR\"\n\
+                       GLOBAL $( MISSING: 150 $)\n\
+                       LET START() BE MISSING(1, 2)\n";
+
+#[test]
+fn call_to_undefined_global() {
+    for symbols in [false, true] {
+        let (mut state, _) = common::load(&common::compile(MISSING));
+        if symbols {
+            state.add_symbols("GLOBAL $( MISSING: 150 $)");
+        }
+        let result = state.interpret();
+        let Err(VmError::UndefinedGlobal { global, name, at }) = &result else {
+            panic!("{:?}", result);
+        };
+        assert_eq!((*global, name.as_deref()), (150, symbols.then_some("MISSING")));
+        assert_eq!(state.instruction_at(at.pc).unwrap().letter(), 'K');
+        let label = if symbols { "G150 (MISSING)" } else { "G150" };
+        let text = format!("CALL TO UNDEFINED GLOBAL {} AT PC {} SP {}", label, at.pc, at.sp);
+        assert_eq!(result.unwrap_err().to_string(), text);
+    }
+}
+
+// A global set to the number of another, unset, global was defined by the
+// program; the call is to a K-code that does not exist.
+#[test]
+fn call_through_global_holding_a_number() {
+    let source = "GET \"LIBHDR\"\n\
+                  GLOBAL $( F: 150; G: 151 $)\n\
+                  LET START() BE $( F := 151; F() $)\n";
+    let (mut state, _) = common::load(&common::compile(source));
+    let result = state.interpret();
+    assert!(matches!(result, Err(VmError::UnknownCall { code: 151, .. })), "{:?}", result);
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use icint::{BcplState, Config, VmError};
+
+#[test]
+fn image_runs_like_the_source() {
+    let intcode = common::compile(&common::shipped("fact.b"));
+    let (state, _) = common::load(&intcode);
+    let mut image = Vec::new();
+    state.write_image(&mut image).unwrap();
+
+    let mut loaded = BcplState::new();
+    loaded.read_image(&image[..]).unwrap();
+    assert_eq!(loaded.memory(), state.memory());
+    assert_eq!((loaded.lomem(), loaded.progstart()), (state.lomem(), state.progstart()));
+    assert_eq!(loaded.disassemble(), state.disassemble());
+
+    let out = icint::stream::SharedBuffer::new();
+    loaded.set_sysprint(Box::new(out.clone()));
+    assert_eq!(loaded.interpret(), Ok(0));
+    loaded.flush();
+    assert_eq!(out.to_string_lossy(), common::run(&intcode).1);
+}
+
+#[test]
+fn image_needs_the_same_geometry() {
+    let (state, _) = common::load(&common::compile(&common::shipped("fact.b")));
+    let mut image = Vec::new();
+    state.write_image(&mut image).unwrap();
+
+    let config = Config { progstart: 500, ..Config::default() };
+    let mut other = BcplState::with_config(&config).unwrap();
+    assert!(matches!(other.read_image(&image[..]), Err(VmError::BadImage(_))));
+    let mut fresh = BcplState::new();
+    let truncated = fresh.read_image(&image[..image.len() / 2]);
+    assert!(matches!(truncated, Err(VmError::BadImage(_))), "{:?}", truncated);
+}
+
+// --save-image writes the image without running, and --image runs it.
+#[test]
+fn image_options() {
+    let intcode = common::compile(&common::shipped("fact.b"));
+    let name = format!("icint-image-{}", std::process::id());
+    let path = std::env::temp_dir().join(name).to_string_lossy().into_owned();
+    let saved = common::icint(&intcode, &[&format!("--save-image={}", path)]);
+    assert!(saved.status.success() && saved.stdout.is_empty(), "{:?}", saved);
+
+    let run = std::process::Command::new(env!("CARGO_BIN_EXE_icint"))
+        .args(["--image", &path])
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(String::from_utf8_lossy(&run.stdout), common::run(&intcode).1);
+
+    let bad = common::icint(&intcode, &["--save-images", &path]);
+    assert_eq!(bad.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&bad.stdout), "INVALID OPTION --save-images\n");
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use icint::stream::SharedBuffer;
+use icint::{Journal, VmError, Word};
+
+const ADD: &str = "GET \"LIBHDR\"\n\
+                   LET START() BE\n\
+                   $( LET N = READN()\n\
+                      LET F = FINDINPUT(\"ADDEND\")\n\
+                      SELECTINPUT(F)\n\
+                      WRITEN(N + READN())\n\
+                   $)\n";
+
+// Runs `intcode` with `journal`, reading `input` as SYSIN if given, and
+// with the stream ADDEND holding `addend` if given.
+fn run_with(
+    intcode: &str,
+    journal: Journal,
+    input: Option<&str>,
+    addend: Option<&str>,
+) -> (Result<Word, VmError>, String) {
+    let (mut state, out) = common::load(intcode);
+    if let Some(input) = input {
+        state.set_sysin(common::input(input));
+    }
+    if let Some(addend) = addend {
+        state.attach_device("ADDEND", common::input(addend));
+    }
+    state.set_journal(Some(journal));
+    let result = state.interpret();
+    state.flush();
+    (result, out.to_string_lossy())
+}
+
+fn log_of(text: &str) -> Journal {
+    Journal::replay(common::input(text))
+}
+
+#[test]
+fn replay_needs_no_input() {
+    let intcode = common::compile(ADD);
+    let log = SharedBuffer::new();
+    let recorded = Journal::record(Box::new(log.clone()));
+    let (result, out) = run_with(&intcode, recorded, Some("12\n"), Some("30\n"));
+    assert_eq!((result, out.as_str()), (Ok(0), "42"));
+
+    // The SYSIN the run was recorded with is a stream of its own, which
+    // the replay does not have; both are SYSIN in the log.
+    let text = log.to_string_lossy();
+    assert!(text.starts_with("ICINT JOURNAL 2\nR 49 SYSIN\nR 50 SYSIN\n"), "{}", text);
+    assert!(text.contains("\nR 51 ADDEND\n"), "{}", text);
+    let (result, out) = run_with(&intcode, log_of(&text), None, None);
+    assert_eq!((result, out.as_str()), (Ok(0), "42"));
+}
+
+#[test]
+fn replay_stops_where_program_differs() {
+    let intcode = common::compile(ADD);
+    let (result, _) = run_with(&intcode, log_of("ICINT JOURNAL 2\nR 49 ADDEND\n"), None, None);
+    let Err(VmError::Replay { msg, .. }) = result else {
+        panic!("{:?}", result);
+    };
+    assert_eq!(msg, "LOG HAS RDCH FROM ADDEND RETURNING 49 WHERE PROGRAM DID RDCH FROM SYSIN");
+
+    let (result, _) = run_with(&intcode, log_of("ICINT JOURNAL 1\nR 1 49\n"), None, None);
+    assert!(matches!(result, Err(VmError::Replay { .. })), "{:?}", result);
+}
+
+#[test]
+fn nondeterministic_kcode_is_replayed() {
+    let source = "GET \"LIBHDR\"\n\
+                  GLOBAL $( CLOCK: 150 $)\n\
+                  LET START() BE WRITEN(CLOCK())\n";
+    let intcode = common::compile(source);
+    let with_clock = |time: Word, journal: Journal| {
+        let (mut state, out) = common::load(&intcode);
+        state.register_kcode(150, move |_, k| {
+            k.a = time;
+            Ok(icint::KResult::Continue)
+        });
+        state.set_nondeterministic(150, true);
+        state.set_journal(Some(journal));
+        assert_eq!(state.interpret(), Ok(0));
+        state.flush();
+        out.to_string_lossy()
+    };
+    let log = SharedBuffer::new();
+    assert_eq!(with_clock(1234, Journal::record(Box::new(log.clone()))), "1234");
+    assert_eq!(log.to_string_lossy(), "ICINT JOURNAL 2\nK 150 1234\n");
+    assert_eq!(with_clock(99, log_of(&log.to_string_lossy())), "1234");
+}
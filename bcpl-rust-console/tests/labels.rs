@@ -0,0 +1,37 @@
+mod common;
+
+use icint::{BcplState, MAX_LABEL, VmError};
+
+// A START whose body is at label `a`, stores 7 in G153 and jumps forward to
+// label `c` to return. Label `b` is the end of the section.
+fn program(a: usize, b: usize, c: usize) -> String {
+    format!("JL{b}\n{a} L7 SG153 JL{c}\n{c} X4\n{b}\nG1L{a}\nZ\n")
+}
+
+#[test]
+fn large_labels() {
+    for labels in [(500, 501, 502), (30000, 1, 999), (MAX_LABEL, MAX_LABEL - 1, 500)] {
+        let mut state = BcplState::new();
+        state.load_str(&program(labels.0, labels.1, labels.2)).unwrap();
+        let top = state.memory().len() - 1;
+        assert_eq!(state.memory()[top], 0, "{:?}", labels);
+        assert_eq!(state.interpret(), Ok(0), "{:?}", labels);
+        assert_eq!(state.global(153), 7, "{:?}", labels);
+        assert_eq!(state.label_addresses(labels.0), vec![state.global(1) as usize]);
+    }
+}
+
+#[test]
+fn label_too_large() {
+    let big = "1".repeat(30);
+    for (label, text) in [(MAX_LABEL + 1, (MAX_LABEL + 1).to_string()), (usize::MAX, big)] {
+        let mut state = BcplState::new();
+        let intcode = format!("JL2\n1 L7 SG153 JL{}\n2\nG1L1\nZ\n", text);
+        let Err(VmError::Assembly(diagnostics)) = state.load_str(&intcode) else {
+            panic!("assembled label {}", text);
+        };
+        let d = &diagnostics[0];
+        assert!(matches!(d.error, VmError::BadLabel { label: l, .. } if l == label), "{:?}", d);
+        assert_eq!(d.line, 2);
+    }
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::time::Duration;
+
+use icint::{Limit, Limits, VmError};
+
+const SPIN: &str = "GET \"LIBHDR\"\n\
+                    LET START() BE $( LET N = 0; N := N + 1 $) REPEAT\n";
+
+const CHATTER: &str = "GET \"LIBHDR\"\n\
+                       LET START() BE WRCH('X') REPEAT\n";
+
+const OPENER: &str = "GET \"LIBHDR\"\n\
+                      LET START() BE FOR I = 1 TO 3 DO FINDINPUT(\"README.md\")\n";
+
+// Runs `source` under `limits`, giving the limit it reached and the output
+// written.
+fn reached(source: &str, limits: Limits) -> (Limit, String) {
+    let (mut state, out) = common::load(&common::compile(source));
+    state.set_limits(limits);
+    let result = state.interpret();
+    state.flush();
+    let Err(VmError::LimitExceeded { limit, at }) = result else {
+        panic!("{:?}", result);
+    };
+    assert_eq!(at.pc, state.registers().pc as usize);
+    (limit, out.to_string_lossy())
+}
+
+#[test]
+fn instruction_limit() {
+    let limits = Limits { instructions: Some(1000), ..Limits::default() };
+    assert_eq!(reached(SPIN, limits).0, Limit::Instructions(1000));
+}
+
+#[test]
+fn time_limit() {
+    let time = Duration::from_millis(50);
+    let limits = Limits { time: Some(time), ..Limits::default() };
+    assert_eq!(reached(SPIN, limits).0, Limit::Time(time));
+}
+
+// Output stops at the limit; the byte over it is not written.
+#[test]
+fn output_limit() {
+    let limits = Limits { output: Some(10), ..Limits::default() };
+    assert_eq!(reached(CHATTER, limits), (Limit::Output(10), "X".repeat(10)));
+}
+
+#[test]
+fn stream_limit() {
+    let limits = Limits { streams: Some(2), ..Limits::default() };
+    assert_eq!(reached(OPENER, limits).0, Limit::Streams(2));
+}
+
+// Each limit has its own exit status.
+#[test]
+fn exit_codes() {
+    let cases = [
+        (SPIN, ["--max-instructions", "1000"], 2),
+        (SPIN, ["--max-time", "0.05"], 3),
+        (CHATTER, ["--max-output", "10"], 4),
+        (OPENER, ["--max-streams", "2"], 5),
+    ];
+    for (source, args, code) in cases {
+        let output = common::icint(&common::compile(source), &args);
+        assert_eq!(output.status.code(), Some(code), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stdout).contains("LIMIT"), "{:?}", output);
+    }
+}
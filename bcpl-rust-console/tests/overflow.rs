@@ -0,0 +1,144 @@
+mod common;
+
+use std::sync::OnceLock;
+
+use icint::stream::SharedBuffer;
+use icint::{BcplState, OverflowCheck, VmError, Word};
+
+// Sets R to a number read from SYSIN.
+const READ: &str = "GET \"LIBHDR\"\n\
+                    GLOBAL $( R: 153 $)\n\
+                    LET START() BE R := READN()\n";
+
+// One more than the largest word, in decimal.
+fn too_big() -> String {
+    (Word::MAX as i64 + 1).to_string()
+}
+
+// A program whose START loads X (G151) and Y (G152), applies `code` and
+// stores the result in R.
+fn arithmetic(code: &str) -> String {
+    format!("JL2\n1 LIG151 LIG152 {} SG153 X4\n2\nG1L1\nZ\n", code)
+}
+
+// Runs `intcode` with `x` and `y` under `check`, giving the machine, the
+// result and the warnings written.
+fn run(
+    intcode: &str,
+    x: Word,
+    y: Word,
+    check: OverflowCheck,
+) -> (BcplState, Result<Word, VmError>, String) {
+    let warnings = SharedBuffer::new();
+    let mut state = BcplState::new();
+    state.set_overflow_check(check);
+    state.set_warnings(Some(Box::new(warnings.clone())));
+    state.load_str(intcode).unwrap();
+    state.set_sysin(common::input(&too_big()));
+    state.set_global(151, x);
+    state.set_global(152, y);
+    let result = state.interpret().map(|_| state.global(153));
+    state.flush();
+    (state, result, warnings.to_string_lossy())
+}
+
+struct Case {
+    intcode: String,
+    x: Word,
+    y: Word,
+    wrapped: Word,
+    // The instruction that overflows
+    op: (char, Word),
+    expr: String,
+}
+
+fn cases() -> Vec<Case> {
+    static READN: OnceLock<String> = OnceLock::new();
+    let (max, min) = (Word::MAX, Word::MIN);
+    let case = |code: &str, x, y, wrapped, op, expr| {
+        Case { intcode: arithmetic(code), x, y, wrapped, op, expr }
+    };
+    let mut cases = vec![
+        // A adds X to the Y just loaded
+        case("AIG151", max, 2, min + 1, ('A', 151), format!("2 + {}", max)),
+        case("X8", max, 2, min + 1, ('X', 8), format!("{} + 2", max)),
+        case("X9", min, 1, max, ('X', 9), format!("{} - 1", min)),
+        case("X5", max, 2, -2, ('X', 5), format!("{} * 2", max)),
+    ];
+    cases.push(Case {
+        intcode: READN.get_or_init(|| common::compile(READ)).clone(),
+        x: 0,
+        y: 0,
+        wrapped: min,
+        op: ('K', 0),
+        expr: format!("READING {}", too_big()),
+    });
+    cases
+}
+
+#[test]
+fn overflow_wraps_silently() {
+    for c in cases() {
+        let (_, result, warnings) = run(&c.intcode, c.x, c.y, OverflowCheck::Off);
+        assert_eq!(result, Ok(c.wrapped), "{}", c.expr);
+        assert_eq!(warnings, "");
+    }
+}
+
+#[test]
+fn overflow_warns() {
+    for c in cases() {
+        let (_, result, warnings) = run(&c.intcode, c.x, c.y, OverflowCheck::Warn);
+        assert_eq!(result, Ok(c.wrapped), "{}", c.expr);
+        assert_eq!(warnings.lines().count(), 1, "{}", warnings);
+        let expected = format!("WARNING ARITHMETIC OVERFLOW {} AT PC ", c.expr);
+        assert!(warnings.starts_with(&expected), "{}", warnings);
+
+        // The same place as the fault would give
+        let (state, result, _) = run(&c.intcode, c.x, c.y, OverflowCheck::Stop);
+        let at = result.unwrap_err().context().unwrap();
+        assert!(warnings.ends_with(&format!(" AT PC {} SP {}\n", at.pc, at.sp)), "{}", warnings);
+        assert_eq!(state.registers().pc as usize, at.pc);
+    }
+}
+
+// The run stops at the instruction that overflowed, for READN the call.
+#[test]
+fn overflow_stops() {
+    for c in cases() {
+        let (state, result, warnings) = run(&c.intcode, c.x, c.y, OverflowCheck::Stop);
+        let Err(VmError::Overflow { expr, at }) = result else {
+            panic!("{}: {:?}", c.expr, result);
+        };
+        assert_eq!((expr, warnings.as_str()), (c.expr.clone(), ""));
+        let ins = state.instruction_at(at.pc).unwrap();
+        let (letter, d) = c.op;
+        assert_eq!(ins.letter(), letter, "{}", c.expr);
+        if letter != 'K' {
+            assert_eq!(ins.d, d, "{}", c.expr);
+        }
+    }
+}
+
+// A number in the INTCODE is checked as it is assembled.
+#[test]
+fn assembler_numbers() {
+    let intcode = format!("JL2\n1 L{} SG153 X4\n2\nG1L1\nZ\n", too_big());
+    let warnings = SharedBuffer::new();
+    let mut state = BcplState::new();
+    state.set_overflow_check(OverflowCheck::Warn);
+    state.set_warnings(Some(Box::new(warnings.clone())));
+    state.load_str(&intcode).unwrap();
+    state.flush();
+    let expected =
+        format!("<string>:2:4: WARNING ARITHMETIC OVERFLOW READING {} AT PC ", too_big());
+    assert!(warnings.to_string_lossy().starts_with(&expected), "{}", warnings.to_string_lossy());
+
+    let mut state = BcplState::new();
+    state.set_overflow_check(OverflowCheck::Stop);
+    let Err(VmError::Assembly(diagnostics)) = state.load_str(&intcode) else {
+        panic!("assembled");
+    };
+    assert!(matches!(diagnostics[0].error, VmError::Overflow { .. }), "{:?}", diagnostics);
+    assert_eq!((diagnostics[0].line, diagnostics[0].column), (2, 4));
+}
@@ -0,0 +1,25 @@
+mod common;
+
+// APTOVEC calls F, which returns with X4 like any other procedure. If the
+// call were missed, that return would drop START from the shadow stack and
+// G would appear directly under STARTUP. syni declares its trees this way.
+#[test]
+fn aptovec_calls_balance() {
+    let source = "GET \"LIBHDR\"\n\
+                  GLOBAL $( F: 150; G: 151 $)\n\
+                  LET F(V, N) BE V!0 := N\n\
+                  LET G() BE RETURN\n\
+                  LET START() BE $( APTOVEC(F, 10); G() $)\n";
+    let (mut state, _) = common::load(&common::compile(source));
+    state.set_profiling(true);
+    assert_eq!(state.interpret(), Ok(0));
+
+    let mut stacks = Vec::new();
+    state.write_profile_stacks(&mut stacks).unwrap();
+    let stacks = String::from_utf8(stacks).unwrap();
+    let (start, f, g) = (state.global(1), state.global(150), state.global(151));
+    for (n, entry) in [(150, f), (151, g)] {
+        let path = format!("STARTUP;G1@{};G{}@{} ", start, n, entry);
+        assert!(stacks.contains(&path), "no {} in\n{}", path, stacks);
+    }
+}
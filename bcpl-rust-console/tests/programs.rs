@@ -0,0 +1,119 @@
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use icint::stream::SharedBuffer;
+use icint::{BcplState, Config, KResult, VmError};
+
+// A START that stores TWICE(21) in G153, with TWICE in G150.
+const TWICE: &str = "JL2\n1 L21 SP4 LIG150 K2 SG153 X4\n2\nG1L1\nZ\n";
+
+// A START that leaves 42 in G150.
+#[test]
+fn load_and_run() {
+    let mut state = BcplState::new();
+    state.load_str("JL2\n1 L42 SG150 X4\n2\nG1L1\nZ\n").unwrap();
+    assert_eq!(state.interpret(), Ok(0));
+    assert_eq!(state.global(150), 42);
+}
+
+#[test]
+fn fact() {
+    let (result, out) = common::run(&common::compile(&common::shipped("fact.b")));
+    assert_eq!(result, Ok(0));
+    let expected: String = [1, 2, 6, 24, 120, 720]
+        .iter()
+        .enumerate()
+        .map(|(i, f)| format!("FACTORIAL OF {} IS {}\n", i + 1, f))
+        .collect();
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn queens() {
+    let (result, out) = common::run(&common::compile(&common::shipped("queens.b")));
+    assert_eq!(result, Ok(0));
+    let counts = [1, 0, 0, 2, 10, 4, 40, 92, 352, 724];
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), counts.len());
+    for (i, (line, count)) in lines.iter().zip(counts).enumerate() {
+        assert_eq!(*line, format!("SOLUTIONS TO {:2}-QUEENS IS {:5}", i + 1, count));
+    }
+}
+
+// The code can start anywhere above the K-codes, if memory has room.
+#[test]
+fn configured_memory() {
+    let config = Config { wordcount: 20000, progstart: 1000 };
+    let mut state = BcplState::with_config(&config).unwrap();
+    let out = SharedBuffer::new();
+    state.load_str(&common::compile(&common::shipped("fact.b"))).unwrap();
+    state.set_sysprint(Box::new(out.clone()));
+    assert_eq!((state.progstart(), state.memory().len()), (1000, 20000));
+    assert!(state.global(1) >= 1000);
+    assert_eq!(state.interpret(), Ok(0));
+    state.flush();
+    assert!(out.to_string_lossy().ends_with("FACTORIAL OF 6 IS 720\n"));
+
+    let small = Config { wordcount: 400, ..Config::default() };
+    assert!(matches!(BcplState::with_config(&small), Err(VmError::BadConfig(_))));
+}
+
+// Code above 32768 has addresses that are negative as 16-bit words.
+#[test]
+#[cfg(not(feature = "word32"))]
+fn high_progstart() {
+    use icint::Config;
+    use icint::stream::SharedBuffer;
+
+    let config = Config { wordcount: 65000, progstart: 33000 };
+    let mut state = BcplState::with_config(&config).unwrap();
+    let out = SharedBuffer::new();
+    state.load_str(&common::compile(&common::shipped("fact.b"))).unwrap();
+    state.set_sysprint(Box::new(out.clone()));
+    assert_eq!(state.interpret(), Ok(0));
+    state.flush();
+    assert!(out.to_string_lossy().ends_with("FACTORIAL OF 6 IS 720\n"));
+}
+
+#[test]
+fn assembly_diagnostics() {
+    let mut state = BcplState::new();
+    let Err(VmError::Assembly(diagnostics)) = state.load_str("1 LIG1\n  JL7 Q\n") else {
+        panic!("assembled");
+    };
+    let d = &diagnostics[0];
+    assert!(matches!(d.error, VmError::BadCh { .. }), "{:?}", d.error);
+    assert_eq!((d.file.as_str(), d.line, d.column), ("<string>", 2, 7));
+    assert_eq!(d.excerpt, "  JL7 Q");
+}
+
+#[test]
+fn host_kcode() {
+    let mut state = BcplState::new();
+    state.load_str(TWICE).unwrap();
+    let seen = Rc::new(Cell::new(0));
+    let arg_seen = seen.clone();
+    state.register_kcode(150, move |s, k| {
+        let arg = s.memory()[k.args()];
+        arg_seen.set(arg);
+        k.a = 2 * arg;
+        Ok(KResult::Continue)
+    });
+    assert_eq!(state.interpret(), Ok(0));
+    assert_eq!(state.global(153), 42);
+    assert_eq!(seen.get(), 21);
+
+    // Without its handler the call is through an undefined global
+    state.unregister_kcode(150);
+    assert!(matches!(state.interpret(), Err(VmError::UndefinedGlobal { global: 150, .. })));
+}
+
+#[test]
+#[should_panic(expected = "not below PROGSTART")]
+fn set_global_in_code_area() {
+    let mut state = BcplState::new();
+    let code = state.progstart();
+    state.set_global(code, 1);
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use icint::stream::SharedBuffer;
+use icint::{BcplState, Protection, VmError};
+
+// Stores over its own first instruction.
+const SELF_MODIFYING: &str = "GET \"LIBHDR\"\n\
+                              LET START() BE $( LET P = START; !P := 0 $)\n";
+
+// Replaces the procedure in F, then changes it again.
+const REPLACING: &str = "GET \"LIBHDR\"\n\
+                         GLOBAL $( F: 150 $)\n\
+                         LET F() BE RETURN\n\
+                         LET START() BE $( F := 1234; F := 5 $)\n";
+
+// A machine with `source` loaded under `protection`, its warnings going to
+// the buffer returned.
+fn protected(source: &str, protection: Protection) -> (BcplState, SharedBuffer) {
+    let (mut state, _) = common::load(&common::compile(source));
+    let warnings = SharedBuffer::new();
+    state.set_warnings(Some(Box::new(warnings.clone())));
+    state.set_protection(protection);
+    (state, warnings)
+}
+
+#[test]
+fn store_to_code() {
+    let (mut state, _) = protected(SELF_MODIFYING, Protection { code: true, globals: false });
+    let start = state.global(1) as usize;
+    let result = state.interpret();
+    let Err(VmError::WriteProtected { addr, at }) = result else {
+        panic!("{:?}", result);
+    };
+    assert_eq!(addr, start);
+    assert_eq!(state.registers().pc as usize, at.pc);
+    assert_eq!(state.instruction_at(at.pc).unwrap().letter(), 'S');
+    let text = format!("STORE TO CODE #{} AT PC {} SP {}", start, at.pc, at.sp);
+    assert_eq!(result.unwrap_err().to_string(), text);
+}
+
+#[test]
+fn store_to_code_unprotected() {
+    let (mut state, _) = protected(SELF_MODIFYING, Protection::default());
+    let start = state.global(1) as usize;
+    assert_eq!(state.interpret(), Ok(0));
+    assert_eq!(state.memory()[start], 0);
+}
+
+// Only the first change to an entry global is reported.
+#[test]
+fn entry_global_warning() {
+    for symbols in [false, true] {
+        let (mut state, warnings) = protected(REPLACING, Protection { code: false, globals: true });
+        let label = if symbols {
+            state.add_symbols("GLOBAL $( F: 150 $)");
+            "G150 (F)"
+        } else {
+            "G150"
+        };
+        let entry = state.global(150);
+        assert_eq!(state.interpret(), Ok(0));
+        state.flush();
+        let text = warnings.to_string_lossy();
+        let expected =
+            format!("WARNING STORE OF 1234 TO {} OVERWRITES ENTRY {} AT PC ", label, entry);
+        assert!(text.starts_with(&expected), "{}", text);
+        assert_eq!(text.lines().count(), 1, "{}", text);
+    }
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use std::fs::{self, File};
+
+use icint::stream::{FileInput, FileOutput, SharedBuffer};
+use icint::{BcplState, Limit, Limits, VmError};
+
+const COUNT: &str = "GET \"LIBHDR\"\n\
+                     LET START() BE FOR I = 1 TO 20 DO WRITEF(\"%N \", I)\n";
+
+// A machine with `intcode` loaded that stops after `n` instructions.
+fn stopped_after(intcode: &str, n: u64) -> (BcplState, SharedBuffer) {
+    let (mut state, out) = common::load(intcode);
+    state.set_limits(Limits { instructions: Some(n), ..Limits::default() });
+    let result = state.interpret();
+    assert!(
+        matches!(result, Err(VmError::LimitExceeded { limit: Limit::Instructions(_), .. })),
+        "{:?}",
+        result
+    );
+    (state, out)
+}
+
+#[test]
+fn snapshot_carries_on() {
+    let intcode = common::compile(COUNT);
+    let (mut state, first) = stopped_after(&intcode, 100);
+    let mut snapshot = Vec::new();
+    state.write_snapshot(&mut snapshot).unwrap();
+
+    // SYSPRINT was supplied by the host, so the restoring machine is set up
+    // the same way to have its own in the same slot.
+    let (mut restored, rest) = common::load(&intcode);
+    restored.read_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(restored.registers(), state.registers());
+    assert_eq!(restored.run(), Ok(0));
+    restored.flush();
+
+    let whole = first.to_string_lossy() + &rest.to_string_lossy();
+    assert_eq!(whole, common::run(&intcode).1);
+    assert!(!rest.to_string_lossy().is_empty());
+}
+
+// A path in the temporary directory for this test run.
+fn temp_path(name: &str) -> String {
+    let name = format!("icint-snapshot-{}-{}", std::process::id(), name);
+    std::env::temp_dir().join(name).to_string_lossy().into_owned()
+}
+
+// Files are reopened where they were, and an output file cut back to what
+// had been written, but only once every file has been found.
+#[test]
+fn file_streams() {
+    let intcode = common::compile(COUNT);
+    let (out, input) = (temp_path("out"), temp_path("in"));
+    fs::write(&input, "").unwrap();
+    let mut state = BcplState::new();
+    state.load_str(&intcode).unwrap();
+    state.set_sysprint(Box::new(FileOutput::new(&out, File::create(&out).unwrap(), 0)));
+    state.set_sysin(Box::new(FileInput::new(&input, File::open(&input).unwrap(), 0)));
+    state.set_limits(Limits { instructions: Some(100), ..Limits::default() });
+    assert!(state.interpret().is_err());
+    let mut snapshot = Vec::new();
+    state.write_snapshot(&mut snapshot).unwrap();
+    drop(state);
+    let saved = fs::read(&out).unwrap();
+    assert!(!saved.is_empty());
+    fs::write(&out, [&saved[..], b"LATER"].concat()).unwrap();
+
+    fs::remove_file(&input).unwrap();
+    let result = BcplState::new().read_snapshot(&snapshot[..]);
+    let missing = format!("CANNOT REOPEN {}", input);
+    assert!(matches!(&result, Err(VmError::BadSnapshot(msg)) if *msg == missing), "{:?}", result);
+    assert!(fs::read(&out).unwrap().ends_with(b"LATER"));
+
+    fs::write(&input, "").unwrap();
+    let mut restored = BcplState::new();
+    restored.read_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(fs::read(&out).unwrap(), saved);
+    assert_eq!(restored.run(), Ok(0));
+    restored.flush();
+    assert_eq!(String::from_utf8(fs::read(&out).unwrap()).unwrap(), common::run(&intcode).1);
+
+    // The length before the saved path
+    let at = snapshot.windows(out.len()).position(|w| w == out.as_bytes()).unwrap();
+    snapshot[at - 4..at].copy_from_slice(&4097u32.to_le_bytes());
+    let result = BcplState::new().read_snapshot(&snapshot[..]);
+    let long = matches!(&result, Err(VmError::BadSnapshot(msg)) if msg == "FILE NAME OF 4097 BYTES");
+    assert!(long, "{:?}", result);
+    let _ = fs::remove_file(&out);
+    let _ = fs::remove_file(&input);
+}
+
+#[test]
+fn bad_snapshot_leaves_machine_alone() {
+    let (mut state, _) = stopped_after(&common::compile(COUNT), 100);
+    let mut snapshot = Vec::new();
+    state.write_snapshot(&mut snapshot).unwrap();
+
+    let (mut other, _) = common::load(&common::compile(COUNT));
+    let before = other.memory().to_vec();
+    for len in [0, 8, 40, snapshot.len() - 1] {
+        let result = other.read_snapshot(&snapshot[..len]);
+        assert!(matches!(result, Err(VmError::BadSnapshot(_))), "{}: {:?}", len, result);
+    }
+    assert_eq!(other.memory(), &before[..]);
+}
+
+// Without allocations the snapshot ends with the heap top and two empty
+// lists.
+#[cfg(feature = "coroutines")]
+#[test]
+fn heap_outside_store() {
+    let (mut state, _) = stopped_after(&common::compile(COUNT), 100);
+    let mut snapshot = Vec::new();
+    state.write_snapshot(&mut snapshot).unwrap();
+    let top = snapshot.len() - 12;
+
+    let mut bad_top = snapshot.clone();
+    bad_top[top..top + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    let mut bad_block = snapshot[..top + 4].to_vec();
+    for n in [1u32, 19000, 5000, 0] {
+        bad_block.extend_from_slice(&n.to_le_bytes());
+    }
+    for bad in [bad_top, bad_block] {
+        let result = BcplState::new().read_snapshot(&bad[..]);
+        let heap = matches!(&result, Err(VmError::BadSnapshot(msg)) if msg.starts_with("HEAP"));
+        assert!(heap, "{:?}", result);
+    }
+}
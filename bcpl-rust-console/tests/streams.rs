@@ -0,0 +1,58 @@
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use icint::stream::{ReadStream, SharedBuffer, Stream};
+use icint::BcplState;
+
+// Copies SYSIN and then the WORDS device to the LINES device, ending each
+// line with NEWLINE, and reports on SYSPRINT.
+const COPY: &str = "GET \"LIBHDR\"\n\
+                    LET COPY() BE\n\
+                    $( LET CH = RDCH()\n\
+                    IF CH = ENDSTREAMCH RETURN\n\
+                    TEST CH = '*N' THEN NEWLINE() ELSE WRCH(CH)\n\
+                    $) REPEAT\n\
+                    LET START() BE\n\
+                    $( SELECTOUTPUT(FINDOUTPUT(\"LINES\"))\n\
+                    COPY()\n\
+                    SELECTINPUT(FINDINPUT(\"WORDS\"))\n\
+                    COPY()\n\
+                    ENDREAD()\n\
+                    ENDWRITE()\n\
+                    WRITES(\"COPIED*N\")\n\
+                    $)\n";
+
+// An output stream that keeps what is written a line at a time.
+#[derive(Clone, Default)]
+struct Lines(Rc<RefCell<(Vec<String>, String)>>);
+
+impl Stream for Lines {
+    fn write_bytes(&mut self, buf: &[u8]) {
+        self.0.borrow_mut().1.push_str(&String::from_utf8_lossy(buf));
+    }
+
+    fn end_line(&mut self) {
+        let (lines, line) = &mut *self.0.borrow_mut();
+        lines.push(std::mem::take(line).trim_end().to_string());
+    }
+}
+
+#[test]
+fn host_streams() {
+    let lines = Lines::default();
+    let out = SharedBuffer::new();
+    let mut state = BcplState::new();
+    state.load_str(&common::compile(COPY)).unwrap();
+    state.set_sysin(Box::new(ReadStream(&b"ONE\nTWO\n"[..])));
+    state.set_sysprint(Box::new(out.clone()));
+    state.attach_device("words", common::input("THREE\nFOUR\n"));
+    state.attach_device("LINES", Box::new(lines.clone()));
+    assert_eq!(state.interpret(), Ok(0));
+    state.flush();
+    assert_eq!(out.to_string_lossy(), "COPIED\n");
+    let (lines, rest) = &*lines.0.borrow();
+    assert_eq!(lines, &["ONE", "TWO", "THREE", "FOUR"]);
+    assert_eq!(rest, "");
+}
@@ -0,0 +1,58 @@
+use icint::{BcplState, Config, VmError};
+
+#[test]
+fn shipped_headers() {
+    let config = Config { progstart: 1000, ..Config::default() };
+    let mut state = BcplState::with_config(&config).unwrap();
+    assert_eq!(state.load_symbols("libhdr"), Ok(31));
+    assert_eq!(state.load_symbols("bcpl-with-coroutines/coroutines"), Ok(2));
+    assert_eq!(state.global_name(76), Some("WRITEF"));
+    assert_eq!(state.global_name(500), Some("CURRCO"));
+    assert_eq!(state.global_named("colist"), Some(501));
+    assert_eq!(state.global_name(2), None);
+
+    let result = state.load_symbols("no-such-header");
+    assert_eq!(result, Err(VmError::NoInput("no-such-header".to_string())));
+}
+
+#[test]
+fn brackets() {
+    let mut state = BcplState::new();
+    let text = "GLOBAL $( A: 150 $)\n\
+                GLOBAL $(TAG B: 151; C: 152 $)TAG\n\
+                GLOBAL { D: 153, E: #232 }\n\
+                GLOBAL $( F: #X9B $) G: 156\n";
+    assert_eq!(state.add_symbols(text), 6);
+    let names: Vec<_> = (150..=156).map(|g| state.global_name(g)).collect();
+    let expected = [Some("A"), Some("B"), Some("C"), Some("D"), Some("E"), Some("F"), None];
+    assert_eq!(names, expected);
+}
+
+// Declarations in comments and strings are not declarations.
+#[test]
+fn comments_and_strings() {
+    let mut state = BcplState::new();
+    let text = "// GLOBAL $( A: 150 $)\n\
+                /* GLOBAL $( B: 151 $)\n   GLOBAL $( C: 152 $) */\n\
+                LET S = \"GLOBAL $( D: 153 $) *\" GLOBAL $( E: 154 $)\"\n\
+                GLOBAL $( // F: 155\n   G: 156 /* H: 157 */ $)\n";
+    assert_eq!(state.add_symbols(text), 1);
+    assert_eq!(state.global_named("G"), Some(156));
+    assert!(["A", "B", "C", "D", "E", "F", "H"].iter().all(|n| state.global_named(n).is_none()));
+}
+
+// A global keeps the first name it is given, whichever file gave it.
+#[test]
+fn duplicate_names() {
+    let mut state = BcplState::new();
+    assert_eq!(state.add_symbols("GLOBAL $( FIRST: 150; OTHER: 151 $)"), 2);
+    assert_eq!(state.add_symbols("GLOBAL $( SECOND: 150; OTHER: 152 $)"), 2);
+    assert_eq!(state.global_name(150), Some("FIRST"));
+    assert_eq!(state.global_named("second"), None);
+    assert_eq!(state.global_name(152), Some("OTHER"));
+    assert_eq!(state.global_named("Other"), Some(151));
+
+    // Globals past the global vector are counted but not named.
+    assert_eq!(state.add_symbols("GLOBAL $( CODE: 401 $)"), 1);
+    assert_eq!(state.global_named("CODE"), None);
+}
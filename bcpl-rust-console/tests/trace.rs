@@ -0,0 +1,120 @@
+mod common;
+
+use std::fs;
+use std::sync::OnceLock;
+
+use icint::stream::SharedBuffer;
+use icint::{BcplState, Trace, TraceFilter};
+
+fn fact() -> &'static str {
+    static INTCODE: OnceLock<String> = OnceLock::new();
+    INTCODE.get_or_init(|| common::compile(&common::shipped("fact.b")))
+}
+
+// A traced line: the instruction's number in the run, its address and its
+// letter.
+#[derive(Clone, Debug, PartialEq)]
+struct Line {
+    count: u64,
+    pc: usize,
+    letter: char,
+}
+
+// Runs fact.b traced through `filter`, giving the lines recorded.
+fn trace(filter: TraceFilter) -> Vec<Line> {
+    let (mut state, _) = common::load(fact());
+    let out = SharedBuffer::new();
+    state.set_trace(Some(Trace::new(Box::new(out.clone()), filter)));
+    assert_eq!(state.interpret(), Ok(0));
+    state.flush();
+    parse(&state, &out.to_string_lossy())
+}
+
+fn parse(state: &BcplState, text: &str) -> Vec<Line> {
+    (text.lines())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let count = fields.next().unwrap().parse().unwrap();
+            let pc = fields.next().unwrap().trim_end_matches(':').parse().unwrap();
+            let letter = state.instruction_at(pc).unwrap().letter();
+            assert!(line.contains(" -> PC="), "{}", line);
+            Line { count, pc, letter }
+        })
+        .collect()
+}
+
+// The lines of the full trace that `keep` passes.
+fn expected(keep: impl Fn(&Line) -> bool) -> Vec<Line> {
+    trace(TraceFilter::default()).into_iter().filter(keep).collect()
+}
+
+#[test]
+fn full_trace() {
+    let lines = trace(TraceFilter::default());
+    assert!(lines.len() > 100);
+    assert!(lines.iter().enumerate().all(|(i, line)| line.count == i as u64 + 1));
+}
+
+#[test]
+fn address_range() {
+    let start = common::load(fact()).0.global(1) as usize;
+    let addrs = start..=start + 20;
+    let lines = trace(TraceFilter { addrs: Some(addrs.clone()), ..Default::default() });
+    assert!(!lines.is_empty());
+    assert_eq!(lines, expected(|line| addrs.contains(&line.pc)));
+}
+
+#[test]
+fn opcode_classes() {
+    let calls = trace(TraceFilter { calls: true, ..Default::default() });
+    assert!(!calls.is_empty());
+    assert_eq!(calls, expected(|line| line.letter == 'K'));
+
+    let jumps = trace(TraceFilter { jumps: true, ..Default::default() });
+    assert!(!jumps.is_empty());
+    assert_eq!(jumps, expected(|line| "JTF".contains(line.letter)));
+
+    let both = trace(TraceFilter { calls: true, jumps: true, ..Default::default() });
+    assert_eq!(both, expected(|line| "KJTF".contains(line.letter)));
+}
+
+#[test]
+fn count_window() {
+    let lines = trace(TraceFilter { count: Some(10..=20), ..Default::default() });
+    let counts: Vec<u64> = lines.iter().map(|line| line.count).collect();
+    assert_eq!(counts, (10..=20).collect::<Vec<_>>());
+}
+
+#[test]
+fn combined_filters() {
+    let start = common::load(fact()).0.global(1) as usize;
+    let addrs = start..=start + 40;
+    let filter = TraceFilter {
+        addrs: Some(addrs.clone()),
+        calls: true,
+        jumps: false,
+        count: Some(50..=400),
+    };
+    let lines = trace(filter);
+    assert!(!lines.is_empty());
+    let keep = |line: &Line| {
+        addrs.contains(&line.pc) && line.letter == 'K' && (50..=400).contains(&line.count)
+    };
+    assert_eq!(lines, expected(keep));
+}
+
+// The command writes the trace to its own file, leaving SYSPRINT alone.
+#[test]
+fn trace_file() {
+    let path = std::env::temp_dir().join(format!("icint-trace-{}", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    let output = common::icint(fact(), &["--trace", &path, "--trace-ops", "calls"]);
+    let text = fs::read_to_string(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    assert!(output.status.success());
+    let (state, _) = common::load(fact());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with("FACTORIAL OF 6 IS 720\n"), "{}", stdout);
+    assert!(!stdout.contains(" -> "), "{}", stdout);
+    assert_eq!(parse(&state, &text), expected(|line| line.letter == 'K'));
+}